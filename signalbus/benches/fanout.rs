@@ -0,0 +1,50 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use signalbus::index::SubscriptionIndex;
+use signalbus::pattern::Pattern;
+
+/// A mix resembling many services listening on their own topics: mostly exact
+/// names, some per-service wildcards and a few cross-cutting globs.
+fn patterns(count: usize) -> Vec<Pattern> {
+    (0..count)
+        .map(|i| {
+            let source = match i % 10 {
+                0 => format!("svc{}:*", i),
+                1 => format!("svc{}.**", i),
+                2 => format!("*.event{}", i),
+                _ => format!("svc{}.event{}", i, i % 7),
+            };
+            Pattern::parse(&source).unwrap()
+        })
+        .collect()
+}
+
+fn fanout(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fanout");
+
+    for count in [10, 100, 1_000, 10_000] {
+        let patterns = patterns(count);
+        let mut index = SubscriptionIndex::new();
+        for (i, pattern) in patterns.iter().enumerate() {
+            index.insert(pattern, i as u64, i);
+        }
+        let name = "svc3.event3";
+
+        group.bench_with_input(BenchmarkId::new("linear", count), &patterns, |b, patterns| {
+            b.iter(|| {
+                patterns.iter()
+                    .enumerate()
+                    .filter(|(_, pattern)| pattern.matches(black_box(name)))
+                    .count()
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("index", count), &index, |b, index| {
+            b.iter(|| index.matches(black_box(name)).len())
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, fanout);
+criterion_main!(benches);
@@ -0,0 +1,51 @@
+use anyhow::Result;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rand::{Rng, rng};
+
+/// Password of the `admin` user in older releases; only accepted with `--insecure-default-admin`.
+pub const DEFAULT_ADMIN_PASSWORD: &str = "admin123";
+
+const ALPHANUMERIC: &str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+
+/// How much of a token is shown when listing tokens.
+const MASKED_PREFIX: usize = 4;
+
+pub fn random_string(len: usize) -> String {
+    let chars: Vec<char> = ALPHANUMERIC.chars().collect();
+    let mut rng = rng();
+    (0..len).map(|_| chars[rng.random_range(0..chars.len())]).collect()
+}
+
+/// A token with all but its first few characters hidden.
+pub fn mask_token(token: &str) -> String {
+    format!("{}…", token.chars().take(MASKED_PREFIX).collect::<String>())
+}
+
+/// Hashes a password with argon2id and a random salt, returning a PHC string.
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt_bytes: [u8; 16] = rng().random();
+    let salt = SaltString::encode_b64(&salt_bytes)
+        .map_err(|e| anyhow::anyhow!("Failed to encode salt: {}", e))?;
+
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow::anyhow!("Failed to hash password: {}", e))?;
+
+    Ok(hash.to_string())
+}
+
+/// Checks a password against a PHC hash; the digest comparison is constant-time.
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Whether a stored value is already a PHC hash rather than a legacy plaintext password.
+pub fn is_password_hash(value: &str) -> bool {
+    PasswordHash::new(value).is_ok()
+}
@@ -1,12 +1,17 @@
-use crate::daemon::SOCKET_PATH;
-use crate::models::{Signal, PersistentSignal};
+use crate::client::{Client, ClientError, Received};
+use crate::models::{Balance, CronSpec, MissedRuns, OverflowPolicy, Permission, Priority, Signal, TokenInfo};
+use crate::protocol::ListenOptions;
+use crate::store::StateSnapshot;
+use crate::wal::FsyncPolicy;
 use anyhow::Result;
-use clap::{Parser, Subcommand};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::UnixStream;
+use clap::{ArgGroup, Parser, Subcommand};
+use futures::StreamExt;
 use tokio::process::Command as TokioCommand;
-use std::process::Stdio;
+use std::collections::HashSet;
+use std::process::{ExitStatus, Stdio};
+use std::path::PathBuf;
 use std::fs;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub const TOKEN_FILE: &str = ".signalbus_token";
 
@@ -26,17 +31,99 @@ pub enum Command {
         payload: Option<String>,
         #[arg(long)]
         ttl: Option<u64>,
+        /// Queued signals are delivered highest priority first
+        #[arg(long, value_enum, default_value_t = Priority::Normal)]
+        priority: Priority,
+        /// Publish at a unix timestamp or RFC 3339 time instead of now
+        #[arg(long, value_parser = parse_at)]
+        at: Option<u64>,
+        /// Publish after a duration such as `30s` or `2h`
+        #[arg(long, value_parser = humantime::parse_duration, conflicts_with = "at")]
+        delay: Option<Duration>,
         #[arg(long)]
         token: Option<String>,
     },
+    /// Emit a request and print the payload of the first reply
+    Request {
+        signal: String,
+        #[arg(long)]
+        payload: Option<String>,
+        /// Seconds to wait for a reply
+        #[arg(long, default_value = "30")]
+        timeout: u64,
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Answer requests with the output of a command
+    Serve {
+        pattern: String,
+        /// Command whose stdout becomes the reply payload
+        #[arg(long)]
+        exec: String,
+        /// Share requests with the other servers in this consumer group
+        #[arg(long)]
+        group: Option<String>,
+        #[arg(long)]
+        token: Option<String>,
+    },
+    // Durable subscriptions always acknowledge, so --ack-timeout and
+    // --max-retries apply to them too.
+    #[command(group(ArgGroup::new("acking").args(["ack", "durable"]).multiple(true)))]
     Listen {
         pattern: String,
         #[arg(long)]
         exec: Option<String>,
+        /// What the daemon does when this listener falls behind
+        #[arg(long, value_enum, default_value = "block")]
+        overflow: OverflowPolicy,
+        /// Milliseconds the `block` policy waits for room before dropping a signal
+        #[arg(long)]
+        block_timeout: Option<u64>,
+        /// Replay history starting at this signal id before live signals
+        #[arg(long)]
+        from_id: Option<u64>,
+        /// Replay history since a unix timestamp, an RFC 3339 time or a duration ago such as `10m`
+        #[arg(long, value_parser = parse_since)]
+        since: Option<u64>,
+        /// Replay the last N matching signals from history
+        #[arg(long)]
+        last: Option<usize>,
+        /// Acknowledge each signal once handled; with --exec only when the command succeeds
+        #[arg(long)]
+        ack: bool,
+        /// Milliseconds before an unacknowledged signal is delivered again (default 30000)
+        #[arg(long, requires = "acking")]
+        ack_timeout: Option<u64>,
+        /// Redeliveries of a failing signal before it moves to the dead-letter queue (default 5)
+        #[arg(long, requires = "acking")]
+        max_retries: Option<u32>,
+        /// Join a consumer group; each signal goes to one member of the group
+        #[arg(long, conflicts_with_all = ["from_id", "since", "last", "durable"])]
+        group: Option<String>,
+        /// How a new group picks the member for a signal
+        #[arg(long, value_enum, requires = "group")]
+        balance: Option<Balance>,
+        /// Attach to a named durable subscription that resumes after the last acknowledged signal
+        #[arg(long, conflicts_with_all = ["from_id", "since", "last"])]
+        durable: Option<String>,
         #[arg(long)]
         token: Option<String>,
     },
-    Daemon,
+    Daemon {
+        #[arg(long)]
+        data_dir: Option<PathBuf>,
+        #[arg(long)]
+        in_memory: bool,
+        #[arg(long, value_enum, default_value = "periodic")]
+        fsync: FsyncPolicy,
+        #[arg(long)]
+        admin_password_file: Option<PathBuf>,
+        #[arg(long)]
+        insecure_default_admin: bool,
+        /// JSON file mapping Unix users and groups to SignalBus users
+        #[arg(long)]
+        peer_auth: Option<PathBuf>,
+    },
     History {
         pattern: String,
         #[arg(short, long, default_value = "10")]
@@ -68,6 +155,9 @@ pub enum Command {
         user_id: String,
         #[arg(short, long)]
         permissions: Vec<String>,
+        /// Limit a permission to signals matching a pattern, e.g. `Write=ci:**`; repeatable
+        #[arg(long = "scope")]
+        scopes: Vec<String>,
         #[arg(long)]
         expires_in: Option<u64>, 
     },
@@ -76,56 +166,291 @@ pub enum Command {
         #[arg(long)]
         admin_token: Option<String>,
     },
+    State {
+        #[command(subcommand)]
+        action: StateCommand,
+    },
+    User {
+        #[command(subcommand)]
+        action: UserCommand,
+    },
+    Durable {
+        #[command(subcommand)]
+        action: DurableCommand,
+    },
+    /// Inspect and cancel signals emitted with --at or --delay
+    Schedule {
+        #[command(subcommand)]
+        action: ScheduleCommand,
+    },
+    /// Publish signals on a cron schedule from the daemon
+    Cron {
+        #[command(subcommand)]
+        action: CronCommand,
+    },
+    /// List, inspect, refresh and revoke tokens
+    Token {
+        #[command(subcommand)]
+        action: TokenCommand,
+    },
+    /// Inspect signals that listeners kept failing on
+    Dlq {
+        #[command(subcommand)]
+        action: DlqCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum DlqCommand {
+    /// List dead letters, oldest first
+    List {
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Show a dead letter with its payload and failure reason
+    Show {
+        id: u64,
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Emit dead letters again and remove them from the queue
+    Replay {
+        #[arg(required_unless_present = "all")]
+        id: Option<u64>,
+        #[arg(long, conflicts_with = "id")]
+        all: bool,
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Delete dead letters without replaying them
+    Purge {
+        #[arg(required_unless_present = "all")]
+        id: Option<u64>,
+        #[arg(long, conflicts_with = "id")]
+        all: bool,
+        #[arg(long)]
+        token: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum TokenCommand {
+    /// List all tokens with their values masked
+    List {
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Show the user and permissions of the current token
+    Info {
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Extend the expiry of the current token, such as a login token
+    Refresh {
+        /// Seconds from now until the token expires; an hour by default and at most
+        #[arg(long)]
+        expires_in: Option<u64>,
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Revoke a token by the id shown in `token list`, or all tokens of a user
+    Revoke {
+        #[arg(required_unless_present = "user")]
+        id: Option<String>,
+        #[arg(long, conflicts_with = "id")]
+        user: Option<String>,
+        #[arg(long)]
+        token: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ScheduleCommand {
+    /// List scheduled signals, soonest first
+    List {
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Cancel a scheduled signal before it is published
+    Cancel {
+        id: u64,
+        #[arg(long)]
+        token: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CronCommand {
+    /// Publish a signal on a schedule such as "0 2 * * *" (daemon local time)
+    Add {
+        signal: String,
+        schedule: String,
+        #[arg(long)]
+        payload: Option<String>,
+        #[arg(long)]
+        ttl: Option<u64>,
+        #[arg(long, value_enum, default_value_t = Priority::Normal)]
+        priority: Priority,
+        /// What to do about runs missed while the daemon was down
+        #[arg(long, value_enum, default_value_t = MissedRuns::Once)]
+        missed: MissedRuns,
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// List cron jobs with their next run
+    List {
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Delete a cron job
+    Remove {
+        id: u64,
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Stop a cron job from running until it is resumed
+    Pause {
+        id: u64,
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Run a paused cron job again from its next scheduled time
+    Resume {
+        id: u64,
+        #[arg(long)]
+        token: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum DurableCommand {
+    /// List durable subscriptions with their cursors and backlog
+    List {
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Delete a durable subscription and discard its backlog
+    Delete {
+        name: String,
+        #[arg(long)]
+        token: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum UserCommand {
+    /// Create a user with a password and permissions
+    Add {
+        user_id: String,
+        #[arg(short, long)]
+        password: String,
+        #[arg(short = 'P', long, value_delimiter = ',')]
+        permissions: Vec<String>,
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Change a user's password (your own, or anyone's with Admin)
+    Passwd {
+        user_id: String,
+        #[arg(short, long)]
+        password: String,
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// List users and their permissions
+    List {
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Delete a user and revoke all of their tokens
+    Delete {
+        user_id: String,
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Replace a user's permissions
+    SetPermissions {
+        user_id: String,
+        #[arg(short = 'P', long, value_delimiter = ',')]
+        permissions: Vec<String>,
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Let a user read and write another user's private namespace
+    Grant {
+        user_id: String,
+        /// The user whose `user.<OWNER>.*` signals become accessible
+        owner: String,
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Take back access to another user's private namespace
+    Ungrant {
+        user_id: String,
+        owner: String,
+        #[arg(long)]
+        token: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum StateCommand {
+    /// Write users, tokens and rate limits to a JSON backup
+    Export {
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Replace users, tokens and rate limits with a JSON backup
+    Import {
+        input: PathBuf,
+        #[arg(long)]
+        token: Option<String>,
+    },
+}
+
+/// A client using the given or saved token, or else the daemon's peer auth.
+/// A saved token the daemon no longer accepts, e.g. an expired login token,
+/// falls back to peer auth too.
+async fn authed_client(token: Option<String>) -> Result<Client> {
+    let client = Client::connect().await?;
+    if let Some(token) = token {
+        return Ok(client.with_token(token));
+    }
+    let Some(saved) = load_token() else {
+        return Ok(client.with_peer_credentials());
+    };
+    let client = client.with_token(saved);
+    match client.token_info().await {
+        Err(ClientError::Unauthorized(_)) => Ok(Client::connect().await?.with_peer_credentials()),
+        _ => Ok(client),
+    }
 }
 
 pub async fn login(user_id: String, password: String) -> Result<()> {
-    let mut stream = UnixStream::connect(SOCKET_PATH).await?;
-    
-    let command = format!("LOGIN|{}|{}\n", user_id, password);
-    println!("Sending: {}", command.trim());  
-    stream.write_all(command.as_bytes()).await?;
-    stream.flush().await?;
-    
-    let mut reader = BufReader::new(&mut stream);
-    let mut response = String::new();
-    println!("Waiting for response...");
-    reader.read_line(&mut response).await?;
-    
-    let response = response.trim();
-    println!("Got: {}", response);  
-    
-    if response.starts_with("TOKEN:") {
-        let token = response.trim_start_matches("TOKEN:");
-        save_token(token)?;
-        println!("Login successful! Token saved to ~/.signalbus_token");
-        println!("Token: {}", token);  
-    } else {
-        eprintln!("Login failed: {}", response);
+    let mut client = Client::connect().await?;
+
+    match client.login(&user_id, &password).await {
+        Ok(token) => {
+            save_token(&token)?;
+            println!("Login successful! Token saved to ~/.signalbus_token");
+            println!("Token: {}", token);
+        }
+        Err(e) => eprintln!("Login failed: {}", e),
     }
-    
+
     Ok(())
 }
 
-pub async fn create_token(user_id: String, permissions: Vec<String>, expires_in: Option<u64>) -> Result<()> {
-    let token = load_token().ok_or_else(|| anyhow::anyhow!("Not logged in"))?;
-    
-    let mut stream = UnixStream::connect(SOCKET_PATH).await?;
-    
-    let perms_str = permissions.join(",");
-    let command = if let Some(expires) = expires_in {
-        format!("CREATE_TOKEN|{}|{}|{}|{}\n", token, user_id, perms_str, expires)
-    } else {
-        format!("CREATE_TOKEN|{}|{}|{}\n", token, user_id, perms_str)
-    };
-    
-    stream.write_all(command.as_bytes()).await?;
-    stream.flush().await?;
-    
-    let mut reader = BufReader::new(&mut stream);
-    let mut response = String::new();
-    reader.read_line(&mut response).await?;
-    
-    println!("{}", response.trim());
+pub async fn create_token(user_id: String, permissions: Vec<String>, scopes: Vec<String>, expires_in: Option<u64>) -> Result<()> {
+    let client = authed_client(None).await?;
+
+    let new_token = client.create_token(&user_id, &permissions, &scopes, expires_in).await?;
+    println!("New token created: {}", new_token);
+    for scope in &scopes {
+        println!("Scope: {}", scope);
+    }
     Ok(())
 }
 
@@ -139,216 +464,261 @@ fn save_token(token: &str) -> Result<()> {
 pub fn load_token() -> Option<String> {
     let mut path = dirs::home_dir()?;
     path.push(TOKEN_FILE);
-    fs::read_to_string(path).ok()
+    fs::read_to_string(path).ok().map(|token| token.trim().to_string())
 }
 
 pub async fn revoke_token(token: String, admin_token: Option<String>) -> Result<()> {
-    let auth_token = admin_token.or_else(load_token).ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+    let client = authed_client(admin_token).await?;
 
-    let mut stream = UnixStream::connect(SOCKET_PATH).await?;
-    
-    let command = format!("REVOKE_TOKEN|{}|{}\n", auth_token, token);
-    stream.write_all(command.as_bytes()).await?;
-    stream.flush().await?;
-    
-    let mut reader = BufReader::new(&mut stream);
-    let mut response = String::new();
-    reader.read_line(&mut response).await?;
-    
-    let response = response.trim();
-    if response == "OK" {
-        println!("Token revoked successfully");
-    } else if response.starts_with("ERROR:") {
-        eprintln!("Failed to revoke token: {}", response);
-    } else {
-        println!("{}", response);
+    match client.revoke_token(&token).await {
+        Ok(()) => println!("Token revoked successfully"),
+        Err(e) => eprintln!("Failed to revoke token: {}", e),
     }
-    
+
     Ok(())
 }
 
-pub async fn emit_signal(signal_name: String, payload: Option<String>, ttl: Option<u64>, token: Option<String>) -> Result<()> {
-    let auth_token = token.or_else(load_token).ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+pub async fn emit_signal(
+    signal_name: String,
+    payload: Option<String>,
+    ttl: Option<u64>,
+    priority: Priority,
+    at: Option<u64>,
+    token: Option<String>,
+) -> Result<()> {
+    let client = authed_client(token).await?;
+    let mut signal = Signal::new(signal_name, payload)?;
+    signal.priority = priority;
 
-    let signal = Signal::new(signal_name, payload)?;
-    
-    let mut stream = UnixStream::connect(SOCKET_PATH).await?;
-    
-    let emit_command = if let Some(ttl_secs) = ttl {
-        format!("EMIT|{}|{}|{}\n", auth_token, serde_json::to_string(&signal)?, ttl_secs)
-    } else {
-        format!("EMIT|{}|{}\n", auth_token, serde_json::to_string(&signal)?)
-    };
-    
-    stream.write_all(emit_command.as_bytes()).await?;
-    stream.flush().await?;
-    
-    let mut reader = BufReader::new(&mut stream);
-    let mut response = String::new();
-    reader.read_line(&mut response).await?;
-    let response = response.trim();
-    
-    if response == "OK" {
-        println!("Signal emitted: {}", signal.name);
-        if let Some(ttl_secs) = ttl {
-            println!("TTL: {} seconds", ttl_secs);
-        }
-        Ok(())
-    } else if response.starts_with("ERROR:") {
-        Err(anyhow::anyhow!("Failed to emit signal: {}", response))
-    } else {
-        println!("Signal emitted: {}", signal.name);
-        if let Some(ttl_secs) = ttl {
-            println!("TTL: {} seconds", ttl_secs);
-        }
-        Ok(())
+    if let Some(at) = at {
+        let scheduled = client.schedule(&signal, ttl, at).await
+            .map_err(|e| anyhow::anyhow!("Failed to schedule signal: {}", e))?;
+        println!("Signal scheduled: {} (id {}) at {}", signal.name, scheduled.id, format_time(scheduled.due_at));
+        return Ok(());
+    }
+
+    client.emit(&signal, ttl).await
+        .map_err(|e| anyhow::anyhow!("Failed to emit signal: {}", e))?;
+
+    println!("Signal emitted: {}", signal.name);
+    if let Some(ttl_secs) = ttl {
+        println!("TTL: {} seconds", ttl_secs);
     }
+    if !priority.is_normal() {
+        println!("Priority: {}", priority);
+    }
+    Ok(())
 }
 
-pub async fn listen_signals(pattern: String, exec_cmd: Option<String>, token: Option<String>) -> Result<()> {
-    let auth_token = token.or_else(load_token).ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+pub async fn listen_signals(
+    pattern: String,
+    exec_cmd: Option<String>,
+    mut options: ListenOptions,
+    token: Option<String>,
+) -> Result<()> {
+    let client = authed_client(token).await?;
 
     println!("Listening for pattern: {}", pattern);
     if let Some(cmd) = &exec_cmd {
         println!("Will execute: {}", cmd);
     }
     
-    let mut stream = UnixStream::connect(SOCKET_PATH).await?;
-    
-    let message = format!("LISTEN|{}|{}\n", auth_token, pattern);
-    stream.write_all(message.as_bytes()).await?;
-    stream.flush().await?;
-    
-    let mut reader = BufReader::new(&mut stream);
-    
-    let mut line = String::new();
-    loop {
-        line.clear();
-        match reader.read_line(&mut line).await {
-            Ok(0) => {
-                println!("Daemon disconnected");
-                break;
-            }
-            Ok(_) => {
-                let line = line.trim();
-                if !line.is_empty() {
-                    match serde_json::from_str::<Signal>(line) {
-                        Ok(signal) => {
-                            println!("Received signal: {}", signal.name);
-                            if let Some(payload) = &signal.payload {
-                                println!("   Payload: {}", payload);
-                            }
-                            println!("   Timestamp: {}", signal.timestamp);
-                            
-                            if let Some(cmd) = &exec_cmd {
-                                if let Err(e) = execute_command(cmd, &signal).await {
-                                    eprintln!("Error executing command: {}", e);
-                                }
-                            }
-                            println!("---");
-                        }
-                        Err(e) => eprintln!("Invalid signal: {}", e),
-                    }
+    let acks = options.acks();
+    if options.group.is_some() {
+        // Leave queued signals in the daemon, where the group can hand them to an idle member.
+        options.prefetch.get_or_insert(1);
+    }
+    let mut subscription = client.subscribe_with(&pattern, options).await?;
+    let mut reported_dropped = 0;
+
+    while let Some(Received { id, signal, redelivered }) = subscription.next().await {
+        let dropped = subscription.dropped();
+        if dropped > reported_dropped {
+            eprintln!("Warning: {} signals dropped because this listener fell behind", dropped - reported_dropped);
+            reported_dropped = dropped;
+        }
+
+        println!("Received signal: {}", signal.name);
+        if let Some(payload) = &signal.payload {
+            println!("   Payload: {}", payload);
+        }
+        println!("   Timestamp: {}", signal.timestamp);
+        println!("   Id: {}", id);
+        if let Some(sender) = &signal.sender {
+            println!("   Sender: {}", sender);
+        }
+        if !signal.priority.is_normal() {
+            println!("   Priority: {}", signal.priority);
+        }
+        if redelivered {
+            println!("   Redelivered");
+        }
+        
+        // The failure reason ends up in the dead-letter queue if the signal keeps failing.
+        let handled = match &exec_cmd {
+            Some(cmd) => match execute_command(cmd, id, &signal).await {
+                Ok(status) if status.success() => Ok(()),
+                Ok(status) => Err(format!("`{}` failed with {}", cmd, status)),
+                Err(e) => {
+                    eprintln!("Error executing command: {}", e);
+                    Err(format!("`{}` could not run: {}", cmd, e))
                 }
-            }
-            Err(e) => {
-                eprintln!("Read error: {}", e);
-                break;
+            },
+            None => Ok(()),
+        };
+        if acks {
+            let settled = match handled {
+                Ok(()) => subscription.ack(id).await,
+                Err(reason) => subscription.nack(id, Some(&reason)).await,
+            };
+            if let Err(e) = settled {
+                eprintln!("Failed to settle signal {}: {}", id, e);
             }
         }
+        println!("---");
     }
-    
+
+    if subscription.dropped() > reported_dropped {
+        eprintln!("Warning: {} signals dropped because this listener fell behind", subscription.dropped() - reported_dropped);
+    }
+    println!("Daemon disconnected");
     Ok(())
 }
 
-async fn execute_command(cmd: &str, signal: &Signal) -> Result<()> {
+async fn execute_command(cmd: &str, id: u64, signal: &Signal) -> Result<ExitStatus> {
     println!("Executing: {}", cmd);
     
+    let mut command = signal_command(cmd, id, signal);
+    command.stdout(Stdio::inherit());
+    
+    let mut child = command.spawn()?;
+    let status = child.wait().await?;
+    
+    if status.success() {
+        println!("Command executed successfully");
+    } else {
+        eprintln!("Command failed with exit code: {}", status);
+    }
+    
+    Ok(status)
+}
+
+/// A shell command with the signal passed in `SIGNALBUS_*` variables.
+fn signal_command(cmd: &str, id: u64, signal: &Signal) -> TokioCommand {
     let mut command = TokioCommand::new("sh");
     command
         .arg("-c")
         .arg(cmd)  
-        .stdout(Stdio::inherit())
         .stderr(Stdio::inherit())
         .env("SIGNALBUS_SIGNAL", &signal.name)
-        .env("SIGNALBUS_TIMESTAMP", signal.timestamp.to_string());
+        .env("SIGNALBUS_SIGNAL_ID", id.to_string())
+        .env("SIGNALBUS_TIMESTAMP", signal.timestamp.to_string())
+        .env("SIGNALBUS_PRIORITY", signal.priority.to_string());
     
     if let Some(payload) = &signal.payload {
         command.env("SIGNALBUS_PAYLOAD", payload.to_string());
     } else {
         command.env("SIGNALBUS_PAYLOAD", "null");
     }
-    
-    let mut child = command.spawn()?;
-    let status = child.wait().await?;
-    
-    if status.success() {
-        println!("Command executed successfully");
-    } else {
-        eprintln!("Command failed with exit code: {}", status);
+    if let Some(correlation_id) = &signal.correlation_id {
+        command.env("SIGNALBUS_CORRELATION_ID", correlation_id);
     }
-    
+    if let Some(sender) = &signal.sender {
+        command.env("SIGNALBUS_SENDER", &sender.user_id);
+        if let Some(token_id) = &sender.token_id {
+            command.env("SIGNALBUS_SENDER_TOKEN_ID", token_id);
+        }
+        if let Some(pid) = sender.pid {
+            command.env("SIGNALBUS_SENDER_PID", pid.to_string());
+        }
+        if let Some(uid) = sender.uid {
+            command.env("SIGNALBUS_SENDER_UID", uid.to_string());
+        }
+    }
+    command
+}
+
+pub async fn send_request(signal_name: String, payload: Option<String>, timeout: u64, token: Option<String>) -> Result<()> {
+    let client = authed_client(token).await?;
+    let signal = Signal::new(signal_name, payload)?;
+
+    let reply = client.request(&signal, Duration::from_secs(timeout)).await?;
+    // Only the payload goes to stdout so scripts can capture it.
+    println!("{}", reply.payload.unwrap_or(serde_json::Value::Null));
+    Ok(())
+}
+
+pub async fn serve_requests(pattern: String, exec_cmd: String, group: Option<String>, token: Option<String>) -> Result<()> {
+    let client = authed_client(token).await?;
+    let options = ListenOptions {
+        prefetch: group.is_some().then_some(1),
+        group,
+        ..Default::default()
+    };
+    let mut requests = client.subscribe_with(&pattern, options).await?;
+    println!("Serving requests for pattern: {}", pattern);
+
+    while let Some(Received { id, signal, .. }) = requests.next().await {
+        if !signal.is_request() {
+            eprintln!("Ignoring {}: it is not a request", signal.name);
+            continue;
+        }
+
+        let payload = match answer_request(&exec_cmd, id, &signal).await {
+            Ok(payload) => payload,
+            Err(e) => {
+                eprintln!("Request {} failed: {}", signal.name, e);
+                Some(serde_json::json!({ "error": e.to_string() }))
+            }
+        };
+        if let Err(e) = client.reply(&signal, payload).await {
+            eprintln!("Failed to reply to {}: {}", signal.name, e);
+        } else {
+            println!("Replied to {}", signal.name);
+        }
+    }
+
+    println!("Daemon disconnected");
     Ok(())
 }
 
+/// Runs the `serve` command for a request and turns its stdout into the
+/// reply payload: JSON when it parses, a string otherwise.
+async fn answer_request(cmd: &str, id: u64, signal: &Signal) -> Result<Option<serde_json::Value>> {
+    let output = signal_command(cmd, id, signal).stdout(Stdio::piped()).output().await?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("`{}` failed with {}", cmd, output.status));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stdout = stdout.trim();
+    if stdout.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(stdout).unwrap_or_else(|_| serde_json::Value::String(stdout.to_string()))))
+}
+
 pub async fn show_history(pattern: String, limit: usize, token: Option<String>) -> Result<()> {
-    let auth_token = token.or_else(load_token).ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+    let client = authed_client(token).await?;
+    let signals = client.history(&pattern, limit).await?;
 
-    println!("Connecting to daemon...");
-    let mut stream = UnixStream::connect(SOCKET_PATH).await?;
-    println!("Connected to daemon");
-    
-    let message = format!("HISTORY|{}|{}|{}\n", auth_token, pattern, limit);
-    println!("Sending: {}", message.trim());
-    
-    stream.write_all(message.as_bytes()).await?;
-    stream.flush().await?;
-    
-    let mut reader = BufReader::new(&mut stream);
-    let mut response = String::new();
-    
-    println!("Waiting for response...");
-    match reader.read_line(&mut response).await {
-        Ok(0) => {
-            println!("Daemon closed connection unexpectedly");
-            return Ok(());
-        }
-        Ok(_) => {
-            let response = response.trim();
-            println!("Raw response: '{}'", response);
-            
-            if response.is_empty() {
-                println!("No history data received");
-                return Ok(());
+    if signals.is_empty() {
+        println!("No recent signals matching '{}'", pattern);
+    } else {
+        println!("Recent signals matching '{}':", pattern);
+        for ps in signals {
+            println!("ID: {} | Signal: {} | Timestamp: {}", 
+                ps.id, ps.signal.name, ps.signal.timestamp);
+            if let Some(payload) = &ps.signal.payload {
+                println!("   Payload: {}", payload);
             }
-            
-            match serde_json::from_str::<Vec<PersistentSignal>>(response) {
-                Ok(signals) => {
-                    if signals.is_empty() {
-                        println!("No recent signals matching '{}'", pattern);
-                    } else {
-                        println!("Recent signals matching '{}':", pattern);
-                        for ps in signals {
-                            println!("ID: {} | Signal: {} | Timestamp: {}", 
-                                ps.id, ps.signal.name, ps.signal.timestamp);
-                            if let Some(payload) = &ps.signal.payload {
-                                println!("   Payload: {}", payload);
-                            }
-                            if let Some(ttl) = ps.ttl {
-                                println!("   TTL: {}s", ttl);
-                            }
-                            println!("---");
-                        }
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Error parsing history: {}", e);
-                    eprintln!("Raw response was: '{}'", response);
-                }
+            if let Some(sender) = &ps.signal.sender {
+                println!("   Sender: {}", sender);
             }
-        }
-        Err(e) => {
-            eprintln!("Error reading response: {}", e);
+            if let Some(ttl) = ps.ttl {
+                println!("   TTL: {}s", ttl);
+            }
+            println!("---");
         }
     }
     
@@ -356,50 +726,351 @@ pub async fn show_history(pattern: String, limit: usize, token: Option<String>)
 }
 
 pub async fn set_rate_limit(pattern: String, max_signals: u32, per_seconds: u64, token: Option<String>) -> Result<()> {
-    let auth_token = token.or_else(load_token).ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+    let client = authed_client(token).await?;
+    client.set_rate_limit(&pattern, max_signals, per_seconds).await?;
+    println!("Rate limit configured successfully");
+    Ok(())
+}
 
-    let mut stream = UnixStream::connect(SOCKET_PATH).await?;
-    
-    let command = format!("RATE_LIMIT|{}|{}|{}|{}\n", auth_token, pattern, max_signals, per_seconds);
-    stream.write_all(command.as_bytes()).await?;
-    stream.flush().await?;
+pub async fn show_rate_limits(token: Option<String>) -> Result<()> {
+    let client = authed_client(token).await?;
+    let limits = client.rate_limits().await?;
 
-    let mut reader = BufReader::new(&mut stream);
-    let mut response = String::new();
-    reader.read_line(&mut response).await?;
+    if limits.is_empty() {
+        println!("No rate limits configured");
+    } else {
+        println!("Configured rate limits:");
+        for limit in limits {
+            println!("  {}: {} signals per {} seconds", limit.pattern, limit.max_signals, limit.per_seconds);
+        }
+    }
     
-    println!("{}", response.trim());
     Ok(())
 }
 
-pub async fn show_rate_limits(token: Option<String>) -> Result<()> {
-    let auth_token = token.or_else(load_token).ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+pub async fn export_state(output: Option<PathBuf>, token: Option<String>) -> Result<()> {
+    let client = authed_client(token).await?;
+    let snapshot = client.export_state().await
+        .map_err(|e| anyhow::anyhow!("Failed to export state: {}", e))?;
+    let pretty = serde_json::to_string_pretty(&snapshot)?;
 
-    let mut stream = UnixStream::connect(SOCKET_PATH).await?;
-    
-    let command = format!("SHOW_RATE_LIMITS|{}\n", auth_token);
-    stream.write_all(command.as_bytes()).await?;
-    stream.flush().await?;
-    
-    let mut reader = BufReader::new(&mut stream);
-    let mut response = String::new();
-    
-    loop {
-        response.clear();
-        match reader.read_line(&mut response).await {
-            Ok(0) => break, 
-            Ok(_) => {
-                let line = response.trim();
-                if !line.is_empty() {
-                    println!("{}", line);
-                }
+    match output {
+        Some(path) => {
+            fs::write(&path, pretty)?;
+            println!("State exported to {}", path.display());
+        }
+        None => println!("{}", pretty),
+    }
+
+    Ok(())
+}
+
+pub async fn import_state(input: PathBuf, token: Option<String>) -> Result<()> {
+    let client = authed_client(token).await?;
+
+    let contents = fs::read_to_string(&input)?;
+    let snapshot: StateSnapshot = serde_json::from_str(&contents)?;
+
+    client.import_state(&snapshot).await
+        .map_err(|e| anyhow::anyhow!("Failed to import state: {}", e))?;
+    println!("State imported from {}", input.display());
+    Ok(())
+}
+
+pub async fn manage_users(action: UserCommand) -> Result<()> {
+    match action {
+        UserCommand::Add { user_id, password, permissions, token } => {
+            authed_client(token).await?.add_user(&user_id, &password, &permissions).await?;
+            println!("User {} created", user_id);
+        }
+        UserCommand::Passwd { user_id, password, token } => {
+            authed_client(token).await?.set_password(&user_id, &password).await?;
+            println!("Password updated for {}", user_id);
+        }
+        UserCommand::List { token } => {
+            let users = authed_client(token).await?.list_users().await?;
+            if users.is_empty() {
+                println!("No users");
             }
-            Err(e) => {
-                eprintln!("Error reading response: {}", e);
-                break;
+            for user in users {
+                let mut namespaces: Vec<&str> = user.namespaces.iter().map(String::as_str).collect();
+                namespaces.sort();
+                let grants = if namespaces.is_empty() { String::new() } else { format!(" namespaces={}", namespaces.join(",")) };
+                println!("{}: {}{}", user.user_id, format_permissions(&user.permissions), grants);
+            }
+        }
+        UserCommand::Delete { user_id, token } => {
+            authed_client(token).await?.delete_user(&user_id).await?;
+            println!("User {} deleted", user_id);
+        }
+        UserCommand::SetPermissions { user_id, permissions, token } => {
+            authed_client(token).await?.set_user_permissions(&user_id, &permissions).await?;
+            println!("Permissions updated for {}", user_id);
+        }
+        UserCommand::Grant { user_id, owner, token } => {
+            authed_client(token).await?.grant_namespace(&user_id, &owner).await?;
+            println!("{} can now access {}'s namespace", user_id, owner);
+        }
+        UserCommand::Ungrant { user_id, owner, token } => {
+            authed_client(token).await?.revoke_namespace(&user_id, &owner).await?;
+            println!("{} can no longer access {}'s namespace", user_id, owner);
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn manage_durables(action: DurableCommand) -> Result<()> {
+    match action {
+        DurableCommand::List { token } => {
+            let durables = authed_client(token).await?.durables().await?;
+            if durables.is_empty() {
+                println!("No durable subscriptions");
+            }
+            for durable in durables {
+                let state = if durable.attached { "attached" } else { "detached" };
+                println!("{} ({}): pattern={} cursor={} pending={} dropped={} [{}]",
+                         durable.name, durable.user_id, durable.pattern, durable.cursor,
+                         durable.pending, durable.dropped, state);
             }
         }
+        DurableCommand::Delete { name, token } => {
+            authed_client(token).await?.delete_durable(&name).await?;
+            println!("Durable subscription {} deleted", name);
+        }
     }
-    
+
+    Ok(())
+}
+
+pub async fn manage_schedule(action: ScheduleCommand) -> Result<()> {
+    match action {
+        ScheduleCommand::List { token } => {
+            let scheduled = authed_client(token).await?.scheduled_signals().await?;
+            if scheduled.is_empty() {
+                println!("No scheduled signals");
+            }
+            for scheduled in scheduled {
+                let payload = scheduled.signal.payload.map(|payload| format!(" payload={}", payload)).unwrap_or_default();
+                println!("#{} {} at {} user={}{}",
+                         scheduled.id, scheduled.signal.name, format_time(scheduled.due_at),
+                         scheduled.user_id, payload);
+            }
+        }
+        ScheduleCommand::Cancel { id, token } => {
+            authed_client(token).await?.cancel_scheduled(id).await?;
+            println!("Scheduled signal {} cancelled", id);
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn manage_cron(action: CronCommand) -> Result<()> {
+    match action {
+        CronCommand::Add { signal, schedule, payload, ttl, priority, missed, token } => {
+            let payload = payload.map(|payload| serde_json::from_str(&payload)).transpose()?;
+            let spec = CronSpec { signal, schedule, payload, ttl, priority, missed };
+            let job = authed_client(token).await?.add_cron_job(&spec).await?;
+            println!("Cron job {} added: {} on '{}', next run at {}",
+                     job.id, job.spec.signal, job.spec.schedule, format_time(job.next_run));
+        }
+        CronCommand::List { token } => {
+            let jobs = authed_client(token).await?.cron_jobs().await?;
+            if jobs.is_empty() {
+                println!("No cron jobs");
+            }
+            for job in jobs {
+                let next = match job.paused {
+                    true => "paused".to_string(),
+                    false => format!("next run {}", format_time(job.next_run)),
+                };
+                let last = job.last_run.map(|last| format!(", last run {}", format_time(last))).unwrap_or_default();
+                println!("#{} {} '{}' missed={} ({}{})",
+                         job.id, job.spec.signal, job.spec.schedule, job.spec.missed, next, last);
+            }
+        }
+        CronCommand::Remove { id, token } => {
+            authed_client(token).await?.remove_cron_job(id).await?;
+            println!("Cron job {} removed", id);
+        }
+        CronCommand::Pause { id, token } => {
+            authed_client(token).await?.pause_cron_job(id).await?;
+            println!("Cron job {} paused", id);
+        }
+        CronCommand::Resume { id, token } => {
+            authed_client(token).await?.resume_cron_job(id).await?;
+            println!("Cron job {} resumed", id);
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn manage_tokens(action: TokenCommand) -> Result<()> {
+    match action {
+        TokenCommand::List { token } => {
+            let tokens = authed_client(token).await?.tokens().await?;
+            if tokens.is_empty() {
+                println!("No tokens");
+            }
+            for info in tokens {
+                let expires = info.expires_at.map(format_time).unwrap_or_else(|| "never".to_string());
+                let last_used = info.last_used.map(format_time).unwrap_or_else(|| "never".to_string());
+                let peer = if info.peer { " [peer]" } else { "" };
+                println!("{} {} {}: {} created={} expires={} last_used={}{}",
+                         info.id, info.masked, info.user_id, format_permissions(&info.permissions),
+                         format_time(info.created_at), expires, last_used, peer);
+            }
+        }
+        TokenCommand::Info { token } => {
+            let info = authed_client(token).await?.token_info().await?;
+            print_token_info(&info);
+        }
+        TokenCommand::Refresh { expires_in, token } => {
+            let info = authed_client(token).await?.refresh_token(expires_in).await?;
+            println!("Token {} now expires at {}", info.id, info.expires_at.map(format_time).unwrap_or_default());
+        }
+        TokenCommand::Revoke { id: Some(id), token, .. } => {
+            authed_client(token).await?.revoke_token_id(&id).await?;
+            println!("Token {} revoked", id);
+        }
+        TokenCommand::Revoke { user: Some(user_id), token, .. } => {
+            let count = authed_client(token).await?.revoke_user_tokens(&user_id).await?;
+            println!("Revoked {} tokens of {}", count, user_id);
+        }
+        TokenCommand::Revoke { .. } => unreachable!("clap requires an id or --user"),
+    }
+
     Ok(())
 }
+
+fn print_token_info(info: &TokenInfo) {
+    println!("User: {}", info.user_id);
+    println!("Token: {} (id {})", info.masked, info.id);
+    println!("Permissions: {}", format_permissions(&info.permissions));
+    let mut scopes: Vec<String> = info.scopes.iter()
+        .flat_map(|(permission, patterns)| patterns.iter().map(move |pattern| format!("{:?}={}", permission, pattern)))
+        .collect();
+    scopes.sort();
+    for scope in scopes {
+        println!("Scope: {}", scope);
+    }
+    println!("Created: {}", format_time(info.created_at));
+    println!("Expires: {}", info.expires_at.map(format_time).unwrap_or_else(|| "never".to_string()));
+    if info.peer {
+        println!("Authenticated by peer credentials");
+    }
+}
+
+fn format_permissions(permissions: &HashSet<Permission>) -> String {
+    let mut names: Vec<String> = permissions.iter().map(|permission| format!("{:?}", permission)).collect();
+    names.sort();
+    names.join(",")
+}
+
+pub async fn manage_dead_letters(action: DlqCommand) -> Result<()> {
+    match action {
+        DlqCommand::List { token } => {
+            let dead_letters = authed_client(token).await?.dead_letters().await?;
+            if dead_letters.is_empty() {
+                println!("No dead letters");
+            }
+            for dead_letter in dead_letters {
+                let durable = dead_letter.durable.map(|name| format!(" durable={}", name)).unwrap_or_default();
+                println!("#{} {} (signal {}) pattern={} user={}{} failures={}: {}",
+                         dead_letter.id, dead_letter.signal.signal.name, dead_letter.signal.id,
+                         dead_letter.pattern, dead_letter.user_id, durable, dead_letter.failures,
+                         dead_letter.reason);
+            }
+        }
+        DlqCommand::Show { id, token } => {
+            let dead_letter = authed_client(token).await?.dead_letter(id).await?;
+            println!("{}", serde_json::to_string_pretty(&dead_letter)?);
+        }
+        DlqCommand::Replay { id, all: _, token } => {
+            let count = authed_client(token).await?.replay_dead_letters(id).await?;
+            println!("Replayed {} dead letters", count);
+        }
+        DlqCommand::Purge { id, all: _, token } => {
+            let count = authed_client(token).await?.purge_dead_letters(id).await?;
+            println!("Purged {} dead letters", count);
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses `--at` into a unix timestamp. RFC 3339 times may leave out the
+/// seconds, as in `2026-10-17T02:00Z`.
+fn parse_at(value: &str) -> Result<u64, String> {
+    if let Ok(timestamp) = value.parse::<u64>() {
+        return Ok(timestamp);
+    }
+    let (time, zone) = value.strip_suffix('Z').map_or((value, ""), |time| (time, "Z"));
+    let time = match time.len() {
+        16 => format!("{}:00{}", time, zone),
+        _ => value.to_string(),
+    };
+    humantime::parse_rfc3339_weak(&time)
+        .map_err(|_| format!("Expected a unix timestamp or RFC 3339 time, got '{}'", value))?
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .map_err(|e| e.to_string())
+}
+
+/// The unix time a `--delay` ends at, rounded up to whole seconds.
+/// Delays too long to represent come out as `u64::MAX`, which the daemon rejects.
+pub fn delay_until(delay: Duration) -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().checked_add(delay) {
+        Some(due) => due.as_secs().saturating_add(u64::from(due.subsec_nanos() > 0)),
+        None => u64::MAX,
+    }
+}
+
+/// Formats a unix time as RFC 3339, or as the bare number past the year
+/// 9999, which RFC 3339 cannot show.
+fn format_time(timestamp: u64) -> String {
+    const LAST_FORMATTED: u64 = 253_402_300_799;
+    match UNIX_EPOCH.checked_add(Duration::from_secs(timestamp)) {
+        Some(time) if timestamp <= LAST_FORMATTED => humantime::format_rfc3339_seconds(time).to_string(),
+        _ => timestamp.to_string(),
+    }
+}
+
+/// Parses `--since` into a unix timestamp.
+fn parse_since(value: &str) -> Result<u64, String> {
+    if let Ok(timestamp) = value.parse::<u64>() {
+        return Ok(timestamp);
+    }
+    if let Ok(time) = humantime::parse_rfc3339_weak(value) {
+        return time.duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .map_err(|e| e.to_string());
+    }
+    let ago = humantime::parse_duration(value)
+        .map_err(|_| format!("Expected a unix timestamp, RFC 3339 time or duration, got '{}'", value))?;
+    SystemTime::now()
+        .checked_sub(ago)
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|elapsed| elapsed.as_secs())
+        .ok_or_else(|| format!("Duration '{}' reaches before the epoch", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_times_past_year_9999_as_numbers() {
+        assert_eq!(format_time(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_time(253_402_300_799), "9999-12-31T23:59:59Z");
+        assert_eq!(format_time(253_402_300_800), "253402300800");
+        assert_eq!(format_time(u64::MAX), u64::MAX.to_string());
+    }
+
+    #[test]
+    fn huge_delays_do_not_overflow() {
+        assert_eq!(delay_until(Duration::MAX), u64::MAX);
+    }
+}
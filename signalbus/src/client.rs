@@ -0,0 +1,774 @@
+use crate::auth::random_string;
+use crate::daemon::SOCKET_PATH;
+use crate::models::{CronJob, CronSpec, DeadLetter, DurableInfo, PersistentSignal, RateLimitInfo, ScheduledSignal, Signal, TokenInfo, UserInfo};
+use crate::protocol::{
+    read_frame, write_frame, ApiError, Command, ErrorCode, ListenOptions, Outcome, Reply, Request,
+    ServerMessage, HELLO_PREFIX, PROTOCOL_VERSION,
+};
+use crate::store::StateSnapshot;
+use futures::{Stream, StreamExt};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid JSON from daemon: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Not authenticated")]
+    NotAuthenticated,
+    #[error("Invalid credentials")]
+    InvalidCredentials,
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error("{0}")]
+    RateLimited(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    AlreadyExists(String),
+    #[error("{0}")]
+    InvalidRequest(String),
+    #[error("Daemon error: {0}")]
+    Daemon(String),
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+    #[error("Unexpected reply from daemon: {0:?}")]
+    UnexpectedReply(Box<Reply>),
+    #[error("Daemon closed the connection")]
+    Disconnected,
+    #[error("No reply within {0:?}")]
+    Timeout(Duration),
+}
+
+impl From<ApiError> for ClientError {
+    fn from(error: ApiError) -> Self {
+        match error.code {
+            ErrorCode::Unauthorized => ClientError::Unauthorized(error.message),
+            ErrorCode::InvalidCredentials => ClientError::InvalidCredentials,
+            ErrorCode::RateLimited => ClientError::RateLimited(error.message),
+            ErrorCode::NotFound => ClientError::NotFound(error.message),
+            ErrorCode::AlreadyExists => ClientError::AlreadyExists(error.message),
+            ErrorCode::InvalidRequest => ClientError::InvalidRequest(error.message),
+            ErrorCode::Internal => ClientError::Daemon(error.message),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, ClientError>;
+
+pub use crate::models::REPLY_PREFIX;
+
+/// Replies are only useful while the requester waits, so they expire from history.
+const REPLY_TTL_SECS: u64 = 60;
+
+/// Events a subscription accepts before the daemon waits for more credit,
+/// unless `ListenOptions::prefetch` says otherwise.
+pub const DEFAULT_PREFETCH: u32 = 64;
+
+type PendingReply = oneshot::Sender<(Outcome, Option<Events>)>;
+
+/// A signal delivered to a subscription, with its history id.
+#[derive(Debug, Clone)]
+pub struct Received {
+    /// Pass as `ListenOptions::from_id` to resume after this signal.
+    pub id: u64,
+    pub signal: Signal,
+    /// Delivered again after a nack or an ack timeout.
+    pub redelivered: bool,
+}
+
+/// Receiving side of one subscription on a connection.
+struct Events {
+    signals: mpsc::UnboundedReceiver<Received>,
+    dropped: Arc<AtomicU64>,
+}
+
+struct Route {
+    signals: mpsc::UnboundedSender<Received>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// State shared between a connection's callers and its reader task.
+#[derive(Default)]
+struct Routes {
+    pending: HashMap<u64, PendingReply>,
+    subscriptions: HashMap<u64, Route>,
+    closed: bool,
+}
+
+/// One multiplexed framed-protocol connection to the daemon. A background
+/// task routes responses to their callers by request id and events to their
+/// subscriptions by subscription id.
+struct Connection {
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+    /// Requests sent without waiting for a response, written by the
+    /// connection's notice task. Queueing never blocks, so it works from
+    /// synchronous code such as `Stream::poll_next`.
+    notices: mpsc::UnboundedSender<Request>,
+    routes: Arc<StdMutex<Routes>>,
+    next_id: AtomicU64,
+}
+
+impl Connection {
+    async fn open(socket_path: &Path) -> Result<Arc<Self>> {
+        let stream = UnixStream::connect(socket_path).await?;
+        let (read_half, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(read_half);
+
+        writer.write_all(format!("{}{}\n", HELLO_PREFIX, PROTOCOL_VERSION).as_bytes()).await?;
+        writer.flush().await?;
+
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(ClientError::Disconnected);
+        }
+        match line.trim().strip_prefix(HELLO_PREFIX).and_then(|v| v.parse::<u32>().ok()) {
+            Some(version) if version == PROTOCOL_VERSION => {}
+            _ => return Err(ClientError::Protocol(format!("Handshake rejected: {}", line.trim()))),
+        }
+
+        let routes = Arc::new(StdMutex::new(Routes::default()));
+        tokio::spawn(route_messages(reader, routes.clone()));
+
+        let writer = Arc::new(Mutex::new(writer));
+        let (notices, pending_notices) = mpsc::unbounded_channel();
+        tokio::spawn(write_notices(writer.clone(), pending_notices, routes.clone()));
+
+        Ok(Arc::new(Connection {
+            writer,
+            notices,
+            routes,
+            next_id: AtomicU64::new(1),
+        }))
+    }
+
+    fn is_closed(&self) -> bool {
+        self.routes.lock().unwrap().closed
+    }
+
+    async fn call(
+        &self,
+        token: Option<&str>,
+        command: Command,
+    ) -> Result<(Reply, Option<Events>)> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut routes = self.routes.lock().unwrap();
+            if routes.closed {
+                return Err(ClientError::Disconnected);
+            }
+            routes.pending.insert(id, tx);
+        }
+
+        let request = Request { id, token: token.map(str::to_string), command };
+        if let Err(e) = write_frame(&mut *self.writer.lock().await, &request).await {
+            self.routes.lock().unwrap().pending.remove(&id);
+            return Err(e.into());
+        }
+
+        match rx.await {
+            Ok((Outcome::Ok(reply), events)) => Ok((reply, events)),
+            Ok((Outcome::Error(e), _)) => Err(e.into()),
+            Err(_) => Err(ClientError::Disconnected),
+        }
+    }
+
+    /// Queues a request without waiting for its response, which the reader
+    /// discards. Fails once the connection is closed.
+    fn notify(&self, token: Option<&str>, command: Command) -> Result<()> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let request = Request { id, token: token.map(str::to_string), command };
+        self.notices.send(request).map_err(|_| ClientError::Disconnected)
+    }
+
+    fn forget_subscription(&self, subscription: u64) {
+        self.routes.lock().unwrap().subscriptions.remove(&subscription);
+    }
+}
+
+/// Reader task of a connection. The event channel of a new subscription is
+/// registered here, before the response is handed over, so no event that
+/// follows the `Listening` reply can be missed.
+async fn route_messages(mut reader: BufReader<OwnedReadHalf>, routes: Arc<StdMutex<Routes>>) {
+    while let Ok(Some(message)) = read_frame::<_, ServerMessage>(&mut reader).await {
+        let mut routes = routes.lock().unwrap();
+        match message {
+            ServerMessage::Response { id, outcome } => {
+                let Some(caller) = routes.pending.remove(&id) else {
+                    continue;
+                };
+                let events = match &outcome {
+                    Outcome::Ok(Reply::Listening { subscription }) => {
+                        let (tx, rx) = mpsc::unbounded_channel();
+                        let dropped = Arc::new(AtomicU64::new(0));
+                        routes.subscriptions.insert(*subscription, Route { signals: tx, dropped: dropped.clone() });
+                        Some(Events { signals: rx, dropped })
+                    }
+                    _ => None,
+                };
+                let _ = caller.send((outcome, events));
+            }
+            ServerMessage::Event { subscription, id, signal, dropped, redelivered } => {
+                if let Some(route) = routes.subscriptions.get(&subscription) {
+                    route.dropped.fetch_add(dropped, Ordering::Relaxed);
+                    let _ = route.signals.send(Received { id, signal, redelivered });
+                }
+            }
+            ServerMessage::Closed { subscription, dropped } => {
+                if let Some(route) = routes.subscriptions.remove(&subscription) {
+                    route.dropped.fetch_add(dropped, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    close_routes(&routes);
+}
+
+/// Notice task of a connection. A failed write means the connection is
+/// broken, so it is closed and its subscriptions end instead of waiting for
+/// credit that never arrived.
+async fn write_notices(
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+    mut notices: mpsc::UnboundedReceiver<Request>,
+    routes: Arc<StdMutex<Routes>>,
+) {
+    while let Some(request) = notices.recv().await {
+        if let Err(e) = write_frame(&mut *writer.lock().await, &request).await {
+            eprintln!("Failed to send request to the daemon: {}", e);
+            close_routes(&routes);
+            break;
+        }
+    }
+}
+
+/// Dropping the senders fails pending calls and ends every subscription.
+fn close_routes(routes: &StdMutex<Routes>) {
+    let mut routes = routes.lock().unwrap();
+    routes.closed = true;
+    routes.pending.clear();
+    routes.subscriptions.clear();
+}
+
+/// Async client for the SignalBus daemon.
+///
+/// Requests and subscriptions share one framed connection that is reopened
+/// once the daemon closes it. Clones share the connection as well.
+#[derive(Clone)]
+pub struct Client {
+    socket_path: PathBuf,
+    token: Option<String>,
+    /// Requests without a token rely on the daemon's peer auth config.
+    peer_credentials: bool,
+    connection: Arc<Mutex<Arc<Connection>>>,
+}
+
+impl Client {
+    /// Connects to the daemon on the default socket.
+    pub async fn connect() -> Result<Self> {
+        Self::connect_to(SOCKET_PATH).await
+    }
+
+    /// Connects to the daemon on a custom socket, failing if it is not running.
+    pub async fn connect_to(socket_path: impl AsRef<Path>) -> Result<Self> {
+        let socket_path = socket_path.as_ref().to_path_buf();
+        let connection = Connection::open(&socket_path).await?;
+        Ok(Client {
+            socket_path,
+            token: None,
+            peer_credentials: false,
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Authenticates as the SignalBus user the daemon maps this process's
+    /// Unix account to, when no token is set.
+    pub fn with_peer_credentials(mut self) -> Self {
+        self.peer_credentials = true;
+        self
+    }
+
+    pub fn set_token(&mut self, token: impl Into<String>) {
+        self.token = Some(token.into());
+    }
+
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    async fn connection(&self) -> Result<Arc<Connection>> {
+        let mut guard = self.connection.lock().await;
+        if guard.is_closed() {
+            *guard = Connection::open(&self.socket_path).await?;
+        }
+        Ok(guard.clone())
+    }
+
+    async fn call(&self, command: Command) -> Result<Reply> {
+        let connection = self.connection().await?;
+        let (reply, _) = connection.call(self.token.as_deref(), command).await?;
+        Ok(reply)
+    }
+
+    async fn call_ok(&self, command: Command) -> Result<()> {
+        match self.call(command).await? {
+            Reply::Ok => Ok(()),
+            other => Err(ClientError::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    fn require_token(&self) -> Result<()> {
+        if self.token.is_some() || self.peer_credentials {
+            Ok(())
+        } else {
+            Err(ClientError::NotAuthenticated)
+        }
+    }
+
+    /// Logs in and keeps the returned token for later requests.
+    pub async fn login(&mut self, user_id: &str, password: &str) -> Result<String> {
+        let command = Command::Login { user_id: user_id.to_string(), password: password.to_string() };
+        match self.call(command).await? {
+            Reply::Token { token } => {
+                self.token = Some(token.clone());
+                Ok(token)
+            }
+            other => Err(ClientError::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    pub async fn emit(&self, signal: &Signal, ttl: Option<u64>) -> Result<()> {
+        self.require_token()?;
+        self.call_ok(Command::Emit { signal: signal.clone(), ttl }).await
+    }
+
+    /// Has the daemon publish a signal at a unix time.
+    pub async fn schedule(&self, signal: &Signal, ttl: Option<u64>, at: u64) -> Result<ScheduledSignal> {
+        self.require_token()?;
+        match self.call(Command::Schedule { signal: signal.clone(), ttl, at }).await? {
+            Reply::Scheduled { scheduled } => Ok(*scheduled),
+            other => Err(ClientError::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    /// Signals the current user scheduled, or all of them for admins, soonest first.
+    pub async fn scheduled_signals(&self) -> Result<Vec<ScheduledSignal>> {
+        self.require_token()?;
+        match self.call(Command::ScheduledSignals).await? {
+            Reply::ScheduledSignals { scheduled } => Ok(scheduled),
+            other => Err(ClientError::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    pub async fn cancel_scheduled(&self, id: u64) -> Result<()> {
+        self.require_token()?;
+        self.call_ok(Command::CancelScheduled { id }).await
+    }
+
+    /// Registers a recurring signal. Only admins manage cron jobs.
+    pub async fn add_cron_job(&self, spec: &CronSpec) -> Result<CronJob> {
+        self.require_token()?;
+        match self.call(Command::AddCronJob { spec: spec.clone() }).await? {
+            Reply::CronJob { job } => Ok(*job),
+            other => Err(ClientError::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    pub async fn cron_jobs(&self) -> Result<Vec<CronJob>> {
+        self.require_token()?;
+        match self.call(Command::CronJobs).await? {
+            Reply::CronJobs { jobs } => Ok(jobs),
+            other => Err(ClientError::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    pub async fn remove_cron_job(&self, id: u64) -> Result<()> {
+        self.require_token()?;
+        self.call_ok(Command::RemoveCronJob { id }).await
+    }
+
+    pub async fn pause_cron_job(&self, id: u64) -> Result<()> {
+        self.require_token()?;
+        self.call_ok(Command::PauseCronJob { id }).await
+    }
+
+    pub async fn resume_cron_job(&self, id: u64) -> Result<()> {
+        self.require_token()?;
+        self.call_ok(Command::ResumeCronJob { id }).await
+    }
+
+    /// Emits a request and waits for the first reply to it.
+    ///
+    /// The request gets a fresh reply-to name and correlation id. The reply
+    /// subscription starts before the request is emitted, so a fast reply is
+    /// never missed; the request expires once nobody waits for it anymore.
+    pub async fn request(&self, signal: &Signal, timeout: Duration) -> Result<Signal> {
+        let mut request = signal.clone();
+        let correlation_id = random_string(16);
+        let reply_to = format!("{}{}", REPLY_PREFIX, random_string(16));
+        request.reply_to = Some(reply_to.clone());
+        request.correlation_id = Some(correlation_id.clone());
+
+        let mut replies = self.subscribe(&reply_to).await?;
+        let ttl = timeout.as_secs().max(1);
+        self.emit(&request, Some(ttl)).await?;
+
+        let reply = tokio::time::timeout(timeout, async {
+            while let Some(Received { signal, .. }) = replies.next().await {
+                if signal.correlation_id.as_deref() == Some(correlation_id.as_str()) {
+                    return Ok(signal);
+                }
+            }
+            Err(ClientError::Disconnected)
+        }).await;
+
+        let _ = replies.unsubscribe().await;
+        reply.unwrap_or(Err(ClientError::Timeout(timeout)))
+    }
+
+    /// Answers a request received from a subscription.
+    pub async fn reply(&self, request: &Signal, payload: Option<serde_json::Value>) -> Result<()> {
+        let reply = request.reply(payload)
+            .ok_or_else(|| ClientError::InvalidRequest(format!("Signal {} is not a request", request.name)))?;
+        self.emit(&reply, Some(REPLY_TTL_SECS)).await
+    }
+
+    /// Subscribes to a pattern on the shared connection. The returned stream
+    /// yields signals until it is unsubscribed or the connection closes.
+    pub async fn subscribe(&self, pattern: &str) -> Result<Subscription> {
+        self.subscribe_with(pattern, ListenOptions::default()).await
+    }
+
+    /// Like `subscribe`, with an overflow policy and other listen options.
+    ///
+    /// The daemon sends at most `prefetch` events ahead of what the stream
+    /// has yielded, so a slow consumer is subject to its overflow policy
+    /// instead of buffering without bound in the client.
+    pub async fn subscribe_with(&self, pattern: &str, mut options: ListenOptions) -> Result<Subscription> {
+        self.require_token()?;
+        let prefetch = *options.prefetch.get_or_insert(DEFAULT_PREFETCH);
+        let connection = self.connection().await?;
+        let command = Command::Listen { pattern: pattern.to_string(), options };
+        match connection.call(self.token.as_deref(), command).await? {
+            (Reply::Listening { subscription }, Some(events)) => Ok(Subscription {
+                id: subscription,
+                token: self.token.clone(),
+                connection,
+                events: events.signals,
+                dropped: events.dropped,
+                prefetch,
+                consumed: 0,
+                unsubscribed: false,
+            }),
+            (other, _) => Err(ClientError::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    pub async fn history(&self, pattern: &str, limit: usize) -> Result<Vec<PersistentSignal>> {
+        self.require_token()?;
+        match self.call(Command::History { pattern: pattern.to_string(), limit }).await? {
+            Reply::History { signals } => Ok(signals),
+            other => Err(ClientError::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    pub async fn set_rate_limit(&self, pattern: &str, max_signals: u32, per_seconds: u64) -> Result<()> {
+        self.require_token()?;
+        self.call_ok(Command::SetRateLimit { pattern: pattern.to_string(), max_signals, per_seconds }).await
+    }
+
+    pub async fn rate_limits(&self) -> Result<Vec<RateLimitInfo>> {
+        self.require_token()?;
+        match self.call(Command::RateLimits).await? {
+            Reply::RateLimits { limits } => Ok(limits),
+            other => Err(ClientError::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    /// Durable subscriptions of the current user, or all of them for admins.
+    pub async fn durables(&self) -> Result<Vec<DurableInfo>> {
+        self.require_token()?;
+        match self.call(Command::ListDurables).await? {
+            Reply::Durables { durables } => Ok(durables),
+            other => Err(ClientError::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    pub async fn delete_durable(&self, name: &str) -> Result<()> {
+        self.require_token()?;
+        self.call_ok(Command::DeleteDurable { name: name.to_string() }).await
+    }
+
+    pub async fn dead_letters(&self) -> Result<Vec<DeadLetter>> {
+        self.require_token()?;
+        match self.call(Command::DeadLetters).await? {
+            Reply::DeadLetters { dead_letters } => Ok(dead_letters),
+            other => Err(ClientError::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    pub async fn dead_letter(&self, id: u64) -> Result<DeadLetter> {
+        self.require_token()?;
+        match self.call(Command::DeadLetter { id }).await? {
+            Reply::DeadLetter { dead_letter } => Ok(*dead_letter),
+            other => Err(ClientError::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    /// Emits one dead letter, or all of them, again. Returns how many were replayed.
+    pub async fn replay_dead_letters(&self, id: Option<u64>) -> Result<usize> {
+        self.require_token()?;
+        match self.call(Command::ReplayDeadLetters { id }).await? {
+            Reply::Count { count } => Ok(count),
+            other => Err(ClientError::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    /// Deletes one dead letter, or all of them. Returns how many were removed.
+    pub async fn purge_dead_letters(&self, id: Option<u64>) -> Result<usize> {
+        self.require_token()?;
+        match self.call(Command::PurgeDeadLetters { id }).await? {
+            Reply::Count { count } => Ok(count),
+            other => Err(ClientError::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    /// Issues a token for a user. `scopes` entries such as `Write=ci:**`
+    /// limit a permission to matching signals.
+    pub async fn create_token(
+        &self,
+        user_id: &str,
+        permissions: &[String],
+        scopes: &[String],
+        expires_in: Option<u64>,
+    ) -> Result<String> {
+        self.require_token()?;
+        let command = Command::CreateToken {
+            user_id: user_id.to_string(),
+            permissions: permissions.to_vec(),
+            scopes: scopes.to_vec(),
+            expires_in,
+        };
+        match self.call(command).await? {
+            Reply::Token { token } => Ok(token),
+            other => Err(ClientError::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    pub async fn revoke_token(&self, token_to_revoke: &str) -> Result<()> {
+        self.require_token()?;
+        self.call_ok(Command::RevokeToken { token: token_to_revoke.to_string() }).await
+    }
+
+    pub async fn revoke_token_id(&self, id: &str) -> Result<()> {
+        self.require_token()?;
+        self.call_ok(Command::RevokeTokenId { id: id.to_string() }).await
+    }
+
+    /// Revokes every token of a user and returns how many were revoked.
+    pub async fn revoke_user_tokens(&self, user_id: &str) -> Result<usize> {
+        self.require_token()?;
+        match self.call(Command::RevokeUserTokens { user_id: user_id.to_string() }).await? {
+            Reply::Count { count } => Ok(count),
+            other => Err(ClientError::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    pub async fn tokens(&self) -> Result<Vec<TokenInfo>> {
+        self.require_token()?;
+        match self.call(Command::ListTokens).await? {
+            Reply::Tokens { tokens } => Ok(tokens),
+            other => Err(ClientError::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    /// Describes the token this client authenticates with.
+    pub async fn token_info(&self) -> Result<TokenInfo> {
+        self.require_token()?;
+        match self.call(Command::TokenInfo).await? {
+            Reply::TokenInfo { token } => Ok(*token),
+            other => Err(ClientError::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    /// Extends the expiry of this client's token to `expires_in` seconds
+    /// from now, or an hour, which is also the most it accepts.
+    pub async fn refresh_token(&self, expires_in: Option<u64>) -> Result<TokenInfo> {
+        self.require_token()?;
+        match self.call(Command::RefreshToken { expires_in }).await? {
+            Reply::TokenInfo { token } => Ok(*token),
+            other => Err(ClientError::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    pub async fn add_user(&self, user_id: &str, password: &str, permissions: &[String]) -> Result<()> {
+        self.require_token()?;
+        self.call_ok(Command::AddUser {
+            user_id: user_id.to_string(),
+            password: password.to_string(),
+            permissions: permissions.to_vec(),
+        }).await
+    }
+
+    pub async fn grant_namespace(&self, user_id: &str, owner: &str) -> Result<()> {
+        self.require_token()?;
+        self.call_ok(Command::GrantNamespace { user_id: user_id.to_string(), owner: owner.to_string() }).await
+    }
+
+    pub async fn revoke_namespace(&self, user_id: &str, owner: &str) -> Result<()> {
+        self.require_token()?;
+        self.call_ok(Command::RevokeNamespace { user_id: user_id.to_string(), owner: owner.to_string() }).await
+    }
+
+    pub async fn set_password(&self, user_id: &str, password: &str) -> Result<()> {
+        self.require_token()?;
+        self.call_ok(Command::SetPassword { user_id: user_id.to_string(), password: password.to_string() }).await
+    }
+
+    pub async fn list_users(&self) -> Result<Vec<UserInfo>> {
+        self.require_token()?;
+        match self.call(Command::ListUsers).await? {
+            Reply::Users { users } => Ok(users),
+            other => Err(ClientError::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    pub async fn delete_user(&self, user_id: &str) -> Result<()> {
+        self.require_token()?;
+        self.call_ok(Command::DeleteUser { user_id: user_id.to_string() }).await
+    }
+
+    pub async fn set_user_permissions(&self, user_id: &str, permissions: &[String]) -> Result<()> {
+        self.require_token()?;
+        self.call_ok(Command::SetUserPermissions {
+            user_id: user_id.to_string(),
+            permissions: permissions.to_vec(),
+        }).await
+    }
+
+    pub async fn export_state(&self) -> Result<StateSnapshot> {
+        self.require_token()?;
+        match self.call(Command::ExportState).await? {
+            Reply::State { snapshot } => Ok(snapshot),
+            other => Err(ClientError::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    pub async fn import_state(&self, snapshot: &StateSnapshot) -> Result<()> {
+        self.require_token()?;
+        self.call_ok(Command::ImportState { snapshot: snapshot.clone() }).await
+    }
+}
+
+/// Stream of signals delivered to a `LISTEN` subscription. It yields
+/// `Received`, which carries the history id that `ack`, `nack` and
+/// `ListenOptions::from_id` take alongside the signal itself. Dropping it
+/// inside a runtime unsubscribes in the background; `unsubscribe` waits for
+/// the daemon.
+pub struct Subscription {
+    id: u64,
+    token: Option<String>,
+    connection: Arc<Connection>,
+    events: mpsc::UnboundedReceiver<Received>,
+    dropped: Arc<AtomicU64>,
+    prefetch: u32,
+    /// Events yielded since credit was last granted.
+    consumed: u32,
+    unsubscribed: bool,
+}
+
+impl Subscription {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Total signals the daemon dropped for this subscription so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Acknowledges a signal of an ack-mode or durable subscription so it is
+    /// not delivered again.
+    pub async fn ack(&self, id: u64) -> Result<()> {
+        self.settle(Command::Ack { subscription: self.id, id }).await
+    }
+
+    /// Reports a signal as failed so the daemon delivers it again, or moves
+    /// it to the dead-letter queue once it failed too often. The reason is
+    /// kept with the dead letter.
+    pub async fn nack(&self, id: u64, reason: Option<&str>) -> Result<()> {
+        self.settle(Command::Nack { subscription: self.id, id, reason: reason.map(str::to_string) }).await
+    }
+
+    async fn settle(&self, command: Command) -> Result<()> {
+        match self.connection.call(self.token.as_deref(), command).await? {
+            (Reply::Ok, _) => Ok(()),
+            (other, _) => Err(ClientError::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    pub async fn unsubscribe(mut self) -> Result<()> {
+        self.unsubscribed = true;
+        self.connection.forget_subscription(self.id);
+        let command = Command::Unsubscribe { subscription: self.id };
+        match self.connection.call(self.token.as_deref(), command).await? {
+            (Reply::Ok, _) => Ok(()),
+            (other, _) => Err(ClientError::UnexpectedReply(Box::new(other))),
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if self.unsubscribed {
+            return;
+        }
+        self.connection.forget_subscription(self.id);
+        if let Ok(runtime) = tokio::runtime::Handle::try_current()
+            && !self.connection.is_closed()
+        {
+            let connection = self.connection.clone();
+            let token = self.token.take();
+            let command = Command::Unsubscribe { subscription: self.id };
+            runtime.spawn(async move {
+                let _ = connection.call(token.as_deref(), command).await;
+            });
+        }
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Received;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Received>> {
+        let polled = self.events.poll_recv(cx);
+        if let Poll::Ready(Some(_)) = &polled {
+            self.consumed += 1;
+            // Grant credit in batches of half the window to keep requests few.
+            if self.consumed >= (self.prefetch / 2).max(1) {
+                let command = Command::Credit { subscription: self.id, count: self.consumed };
+                self.consumed = 0;
+                // A closed connection ends the stream on the next poll.
+                let _ = self.connection.notify(self.token.as_deref(), command);
+            }
+        }
+        polled
+    }
+}
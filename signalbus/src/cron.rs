@@ -0,0 +1,83 @@
+use crate::models::CronJob;
+use crate::json_file::JsonFile;
+use anyhow::Result;
+use chrono::{Local, TimeZone};
+use croner::Cron;
+use std::path::Path;
+
+const CRON_FILE: &str = "cron.json";
+
+/// The first time after `after` that a cron expression matches, in the
+/// daemon's local time zone.
+pub fn next_run(schedule: &str, after: u64) -> Result<u64, String> {
+    let cron = Cron::new(schedule)
+        .parse()
+        .map_err(|e| format!("Invalid cron schedule '{}': {}", schedule, e))?;
+    let after = Local.timestamp_opt(after as i64, 0)
+        .single()
+        .ok_or_else(|| format!("Time {} is out of range", after))?;
+    let next = cron.find_next_occurrence(&after, false)
+        .map_err(|e| format!("Cron schedule '{}' never runs: {}", schedule, e))?;
+    Ok(next.timestamp() as u64)
+}
+
+/// Recurring signals by id.
+pub struct CronJobs {
+    entries: Vec<CronJob>,
+    next_id: u64,
+    file: JsonFile,
+}
+
+impl CronJobs {
+    pub fn in_memory() -> Self {
+        CronJobs { entries: Vec::new(), next_id: 1, file: JsonFile::in_memory() }
+    }
+
+    pub async fn open(dir: &Path) -> Result<Self> {
+        let (file, entries): (_, Vec<CronJob>) = JsonFile::open(dir, CRON_FILE).await?;
+        let next_id = entries.iter().map(|entry| entry.id + 1).max().unwrap_or(1);
+        Ok(CronJobs { entries, next_id, file })
+    }
+
+    /// Adds a job, assigning its id.
+    pub fn push(&mut self, mut job: CronJob) -> CronJob {
+        job.id = self.next_id;
+        self.next_id += 1;
+        self.entries.push(job.clone());
+        job
+    }
+
+    pub fn list(&self) -> Vec<CronJob> {
+        self.entries.clone()
+    }
+
+    pub fn get(&self, id: u64) -> Option<&CronJob> {
+        self.entries.iter().find(|entry| entry.id == id)
+    }
+
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut CronJob> {
+        self.entries.iter_mut().find(|entry| entry.id == id)
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<CronJob> {
+        let position = self.entries.iter().position(|entry| entry.id == id)?;
+        Some(self.entries.remove(position))
+    }
+
+    /// Active jobs whose next run is at or before `now`.
+    pub fn due(&self, now: u64) -> Vec<CronJob> {
+        self.entries.iter()
+            .filter(|entry| !entry.paused && entry.next_run <= now)
+            .cloned()
+            .collect()
+    }
+
+    /// When the soonest active job runs.
+    pub fn next_due(&self) -> Option<u64> {
+        self.entries.iter().filter(|entry| !entry.paused).map(|entry| entry.next_run).min()
+    }
+
+    pub async fn save(&self) -> Result<()> {
+        self.file.save(&self.entries).await
+    }
+}
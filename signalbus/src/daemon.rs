@@ -1,17 +1,66 @@
-use crate::models::{Signal, PersistentSignal, pattern_match, Permission, AuthToken};
+use crate::auth;
+use crate::cron::{self, CronJobs};
+use crate::dlq::DeadLetters;
+use crate::schedule::Schedule;
+use crate::durable::{Attachment, Detached, Durables};
+use crate::models::{Balance, CronJob, CronSpec, DeadLetter, DurableInfo, Signal, PersistentSignal, parse_permissions, Permission, AuthToken, OverflowPolicy, MissedRuns, Priority, ScheduledSignal, RateLimitInfo, Sender, TokenInfo, User, UserInfo};
+use crate::index::SubscriptionIndex;
+use crate::namespace::Access;
+use crate::pattern::{Pattern, SEPARATORS};
+use crate::peer::PeerAuth;
+use crate::queue::{self, TrySendError};
+use crate::protocol::{ApiError, ErrorCode, ListenOptions, HELLO_PREFIX, PROTOCOL_VERSION};
+use crate::session;
+use crate::store::{StateSnapshot, StateStore};
+use crate::wal::{self, FsyncPolicy, Wal};
 use anyhow::Result;
-use async_channel::Sender;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{SystemTime, UNIX_EPOCH, Duration, Instant};
 use tokio::fs;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{UnixListener, UnixStream};
-use tokio::sync::Mutex;
-use rand::{Rng, rng};
+use tokio::net::unix::UCred;
+use tokio::sync::{Mutex, Notify};
 
 pub const SOCKET_PATH: &str = "/tmp/signalbus.sock";
+pub const ADMIN_PASSWORD_ENV: &str = "SIGNALBUS_ADMIN_PASSWORD";
+const ADMIN_PASSWORD_FILE: &str = "admin-password";
+/// Signals queued per subscriber before its overflow policy applies.
+const SUBSCRIBER_QUEUE: usize = 100;
+/// A cron run this late counts as missed, e.g. because the daemon was down.
+const CRON_GRACE: Duration = Duration::from_secs(60);
+/// How long a scheduled signal that failed to publish waits to be retried.
+const SCHEDULE_RETRY: u64 = 5;
+/// How far ahead a signal can be scheduled, about ten years.
+const MAX_SCHEDULE_AHEAD: u64 = 10 * 365 * 24 * 3600;
+const TOKEN_ID_LEN: usize = 12;
+/// Seconds a login token is valid for, and the default for `refresh_token`.
+const LOGIN_TOKEN_TTL: u64 = 3600;
+pub const DEFAULT_BLOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub struct DaemonConfig {
+    /// Directory holding the write-ahead log and the state file; `None` keeps
+    /// everything in memory only.
+    pub data_dir: Option<PathBuf>,
+    pub fsync: FsyncPolicy,
+    /// File holding the initial admin password, used when the env var is unset.
+    pub admin_password_file: Option<PathBuf>,
+    /// Allow the well-known `admin123` admin password.
+    pub insecure_default_admin: bool,
+    /// JSON file mapping Unix users and groups to SignalBus users; without
+    /// it every client needs a token.
+    pub peer_auth: Option<PathBuf>,
+}
+
+impl DaemonConfig {
+    pub fn default_data_dir() -> Option<PathBuf> {
+        dirs::data_dir().map(|dir| dir.join("signalbus"))
+    }
+}
 
 #[derive(Clone)]
 struct RateLimitRule {
@@ -19,51 +68,165 @@ struct RateLimitRule {
     time_window: Duration,
 }
 
-#[derive(Clone)]
-#[allow(dead_code)]
-struct User {
-    pub user_id: String,
-    pub password_hash: String, 
-    pub permissions: HashSet<Permission>,
+struct Subscriber {
+    tx: queue::Sender,
+    group: Option<String>,
+    /// Private namespaces whose signals this subscriber may receive.
+    access: Access,
+    policy: OverflowPolicy,
+    block_timeout: Duration,
+    dropped: Arc<AtomicU64>,
+}
+
+/// Receiving side of a subscription, held by the connection that owns it.
+pub struct SubscriberHandle {
+    pub id: u64,
+    pub rx: queue::Receiver,
+    /// Signals discarded by the overflow policy and not yet reported.
+    pub dropped: Arc<AtomicU64>,
+    /// Matching history to send before anything from `rx`, in id order.
+    pub replay: Vec<PersistentSignal>,
+    /// Ids already in history when the subscription started. Live signals
+    /// with these ids were either replayed or predate the replay window.
+    pub seen: HashSet<u64>,
+}
+
+/// Members of a consumer group share its signals instead of each getting all.
+struct ConsumerGroup {
+    balance: Balance,
+    members: usize,
+    /// Rotates the starting member between signals.
+    next: usize,
+}
+
+enum Delivery {
+    Delivered,
+    Dropped,
+    Closed,
 }
 
 pub struct DaemonState {
-    subscribers: Mutex<HashMap<String, Vec<Sender<Signal>>>>,
+    subscribers: Mutex<SubscriptionIndex<Arc<Subscriber>>>,
+    /// Source of subscription ids, shared by live and durable subscriptions.
+    next_subscription: AtomicU64,
+    durables: Mutex<Durables>,
+    groups: Mutex<HashMap<String, ConsumerGroup>>,
+    dead_letters: Mutex<DeadLetters>,
+    schedule: Mutex<Schedule>,
+    /// Wakes the scheduler when a signal is scheduled or cancelled.
+    schedule_changed: Notify,
+    cron_jobs: Mutex<CronJobs>,
+    /// Wakes the cron task when a job is added, removed, paused or resumed.
+    cron_changed: Notify,
     signal_history: Mutex<VecDeque<PersistentSignal>>,
+    wal: Option<Mutex<Wal>>,
     max_history_size: usize,
     next_id: AtomicU64,
-    rate_limits: Mutex<HashMap<String, RateLimitRule>>,
+    rate_limits: Mutex<HashMap<Pattern, RateLimitRule>>,
     signal_counters: Mutex<HashMap<String, VecDeque<Instant>>>,
     users: Mutex<HashMap<String, User>>, 
     auth_tokens: Mutex<HashMap<String, AuthToken>>, 
-    default_tokens: Mutex<HashMap<String, String>>
+    default_tokens: Mutex<HashMap<String, String>>,
+    peer_auth: Option<PeerAuth>,
+    store: Option<Mutex<StateStore>>,
 }
 
 impl DaemonState {
-    pub async fn new() -> Arc<Self> {
+    pub async fn new(config: &DaemonConfig) -> Result<Arc<Self>> {
         println!("[DAEMON] Creating new DaemonState..."); 
 
+        let max_history_size = 1000;
+        let mut history = VecDeque::new();
+        let mut next_id = 1;
+
+        let mut snapshot = None;
+        // Durable subscriptions may still need signals older than the history window.
+        let mut wal_signals = Vec::new();
+
+        let mut dead_letters = DeadLetters::in_memory();
+        let mut schedule = Schedule::in_memory();
+        let mut cron_jobs = CronJobs::in_memory();
+
+        let (wal, store) = match &config.data_dir {
+            Some(data_dir) => {
+                let (wal, mut replay) = Wal::open(&data_dir.join("wal"), config.fsync).await?;
+                wal_signals = replay.signals.clone();
+                wal::retain_live(&mut replay.signals, max_history_size);
+                history.extend(replay.signals);
+                next_id = replay.next_id;
+
+                let (store, loaded) = StateStore::open(data_dir).await?;
+                snapshot = loaded;
+                dead_letters = DeadLetters::open(data_dir).await?;
+                schedule = Schedule::open(data_dir).await?;
+                cron_jobs = CronJobs::open(data_dir).await?;
+                (Some(Mutex::new(wal)), Some(Mutex::new(store)))
+            }
+            None => (None, None),
+        };
+
+        let peer_auth = match &config.peer_auth {
+            Some(path) => Some(PeerAuth::load(path).await?),
+            None => None,
+        };
+
         let state = Arc::new(Self {
-            subscribers: Mutex::new(HashMap::new()),
-            signal_history: Mutex::new(VecDeque::new()),
-            max_history_size: 1000,
-            next_id: AtomicU64::new(1),
+            subscribers: Mutex::new(SubscriptionIndex::new()),
+            next_subscription: AtomicU64::new(1),
+            durables: Mutex::new(Durables::default()),
+            groups: Mutex::new(HashMap::new()),
+            dead_letters: Mutex::new(dead_letters),
+            schedule: Mutex::new(schedule),
+            schedule_changed: Notify::new(),
+            cron_jobs: Mutex::new(cron_jobs),
+            cron_changed: Notify::new(),
+            signal_history: Mutex::new(history),
+            wal,
+            max_history_size,
+            next_id: AtomicU64::new(next_id),
             rate_limits: Mutex::new(HashMap::new()),
             signal_counters: Mutex::new(HashMap::new()),
             users: Mutex::new(HashMap::new()),
             auth_tokens: Mutex::new(HashMap::new()),
             default_tokens: Mutex::new(HashMap::new()),
+            peer_auth,
+            store,
         });
-            
-        println!("[DAEMON] DaemonState created, initializing default users...");
-        state.initialize_default_users().await;
-        println!("[DAEMON] Default users initialized successfully");
 
-        state
+        if let Some(snapshot) = snapshot {
+            println!("[DAEMON] Restoring {} users, {} tokens and {} rate limits from state file",
+                     snapshot.users.len(), snapshot.tokens.len(), snapshot.rate_limits.len());
+            check_snapshot_users(&snapshot)?;
+            if state.restore(snapshot).await {
+                state.persist_state().await;
+            }
+            {
+                let mut durables = state.durables.lock().await;
+                durables.rebuild(&wal_signals);
+                durables.expire(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs());
+            }
+            state.cleanup_expired_tokens().await;
+        }
+
+        if state.users.lock().await.is_empty() {
+            println!("[DAEMON] DaemonState created, initializing default users...");
+            state.initialize_default_users(config).await?;
+            println!("[DAEMON] Default users initialized successfully");
+        } else if !config.insecure_default_admin && state.has_default_admin_password().await {
+            return Err(anyhow::anyhow!(
+                "The admin user still has the well-known default password; change it with \
+                 `signalbus user passwd admin` or start with --insecure-default-admin"
+            ));
+        }
+
+        Ok(state)
     }
 
-    async fn initialize_default_users(&self) {
+    async fn initialize_default_users(&self, config: &DaemonConfig) -> Result<()> {
         println!("[DAEMON] Starting initialize_default_users...");
+
+        let password = resolve_admin_password(config).await?;
+        let password_hash = auth::hash_password(&password)?;
         
         let user_id = "admin".to_string();
         {
@@ -81,8 +244,9 @@ impl DaemonState {
             println!("[DAEMON] Creating admin user...");
             users.insert(user_id.clone(), User {
                 user_id: user_id.clone(),
-                password_hash: "admin123".to_string(), 
+                password_hash,
                 permissions: admin_perms,
+                namespaces: HashSet::new(),
             });
             
         }
@@ -95,8 +259,16 @@ impl DaemonState {
             println!("[DAEMON] Acquired default_tokens lock");
             default_tokens.insert("admin".to_string(), token);
         }
+        self.persist_state().await;
         
         println!("[DAEMON] initialize_default_users completed");
+        Ok(())
+    }
+
+    async fn has_default_admin_password(&self) -> bool {
+        self.users.lock().await
+            .get("admin")
+            .is_some_and(|admin| auth::verify_password(auth::DEFAULT_ADMIN_PASSWORD, &admin.password_hash))
     }
     
     pub async fn add_user(&self, user_id: String, password_hash: String, permissions: HashSet<Permission>) {
@@ -105,58 +277,277 @@ impl DaemonState {
             user_id,
             password_hash,
             permissions,
+            namespaces: HashSet::new(),
         });
+        drop(users);
+        self.persist_state().await;
     }
-    
-    pub async fn authenticate(&self, token: &str, required_permission: Option<Permission>) -> bool {
-        let tokens = self.auth_tokens.lock().await;
-        
-        if let Some(auth_token) = tokens.get(token) {
-            if let Some(expires_at) = auth_token.expires_at {
-                let now = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
-                if now > expires_at {
-                    return false;
+
+    async fn snapshot(&self) -> StateSnapshot {
+        let users = self.users.lock().await.values().cloned().collect();
+        let tokens = self.auth_tokens.lock().await.values().filter(|token| !token.peer).cloned().collect();
+        let default_tokens = self.default_tokens.lock().await.clone();
+        let rate_limits = self.list_rate_limits().await;
+        let durables = self.durables.lock().await.records();
+
+        StateSnapshot { users, tokens, default_tokens, rate_limits, durables }
+    }
+
+    /// Replaces the in-memory state with a snapshot. Returns true when legacy
+    /// plaintext passwords were found and re-hashed, so the caller can persist.
+    async fn restore(&self, snapshot: StateSnapshot) -> bool {
+        let mut migrated = false;
+        let mut users = HashMap::new();
+        for mut user in snapshot.users {
+            if !auth::is_password_hash(&user.password_hash) {
+                match auth::hash_password(&user.password_hash) {
+                    Ok(hash) => {
+                        user.password_hash = hash;
+                        migrated = true;
+                    }
+                    Err(e) => eprintln!("Failed to hash password for {}: {}", user.user_id, e),
                 }
             }
-            
-            if let Some(required_perm) = required_permission {
-                auth_token.permissions.contains(&required_perm) || 
-                auth_token.permissions.contains(&Permission::Admin)
-            } else {
-                true
+            users.insert(user.user_id.clone(), user);
+        }
+        *self.users.lock().await = users;
+        *self.auth_tokens.lock().await = snapshot.tokens
+            .into_iter()
+            .map(|mut token| {
+                if token.id.is_empty() {
+                    token.id = auth::random_string(TOKEN_ID_LEN);
+                    migrated = true;
+                }
+                (token.token.clone(), token)
+            })
+            .collect();
+        *self.default_tokens.lock().await = snapshot.default_tokens;
+        *self.rate_limits.lock().await = snapshot.rate_limits
+            .into_iter()
+            .filter_map(|limit| match Pattern::parse(&limit.pattern) {
+                Ok(pattern) => Some((pattern, RateLimitRule {
+                    max_signals: limit.max_signals,
+                    time_window: Duration::from_secs(limit.per_seconds),
+                })),
+                Err(e) => {
+                    eprintln!("Dropping rate limit: {}", e);
+                    None
+                }
+            })
+            .collect();
+        self.signal_counters.lock().await.clear();
+
+        let mut durables = Durables::restore(snapshot.durables);
+        durables.rebuild(self.signal_history.lock().await.iter());
+        std::mem::replace(&mut *self.durables.lock().await, durables).close();
+        migrated
+    }
+
+    /// Writes the current users, tokens and rate limits to the state file.
+    /// Callers must not hold any of those locks. The store lock is taken
+    /// first so concurrent mutations can never persist an older snapshot last.
+    async fn persist_state(&self) {
+        if let Some(store) = &self.store {
+            let store = store.lock().await;
+            let snapshot = self.snapshot().await;
+            if let Err(e) = store.save(&snapshot).await {
+                eprintln!("Failed to persist daemon state: {}", e);
             }
-        } else {
-            false
         }
     }
 
-    pub async fn generate_token(&self, user_id: String, expires_in: Option<u64>) -> String {
-        let token: String = {
-            let chars: Vec<char> = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789".chars().collect();
-            let mut rng = rng();
-            (0..32).map(|_| chars[rng.random_range(0..chars.len())]).collect()
+    pub async fn export_state(&self) -> StateSnapshot {
+        self.snapshot().await
+    }
+
+    pub async fn create_user(&self, user_id: &str, password: &str, permissions: HashSet<Permission>) -> Result<(), ApiError> {
+        check_user_id(user_id)?;
+        if self.users.lock().await.contains_key(user_id) {
+            return Err(ApiError::new(ErrorCode::AlreadyExists, format!("User already exists: {}", user_id)));
+        }
+        let password_hash = auth::hash_password(password).map_err(ApiError::internal)?;
+        self.add_user(user_id.to_string(), password_hash, permissions).await;
+        Ok(())
+    }
+
+    pub async fn set_password(&self, user_id: &str, password: &str) -> Result<(), ApiError> {
+        let password_hash = auth::hash_password(password).map_err(ApiError::internal)?;
+        {
+            let mut users = self.users.lock().await;
+            let user = users.get_mut(user_id)
+                .ok_or_else(|| ApiError::not_found(format!("Unknown user: {}", user_id)))?;
+            user.password_hash = password_hash;
+        }
+        self.persist_state().await;
+        Ok(())
+    }
+
+    /// Replaces a user's permissions. Existing tokens of the user lose any
+    /// permission that was taken away; new grants apply to tokens issued later.
+    pub async fn set_user_permissions(&self, user_id: &str, permissions: HashSet<Permission>) -> Result<(), ApiError> {
+        {
+            let mut users = self.users.lock().await;
+            let user = users.get_mut(user_id)
+                .ok_or_else(|| ApiError::not_found(format!("Unknown user: {}", user_id)))?;
+            user.permissions = permissions.clone();
+        }
+        {
+            let mut tokens = self.auth_tokens.lock().await;
+            for token in tokens.values_mut().filter(|t| t.user_id == user_id) {
+                token.permissions.retain(|p| permissions.contains(p));
+            }
+        }
+        self.persist_state().await;
+        Ok(())
+    }
+
+    /// Lets a user read and write another user's private namespace, or takes that back.
+    pub async fn set_namespace_grant(&self, user_id: &str, owner: &str, granted: bool) -> Result<(), ApiError> {
+        {
+            check_user_id(owner)?;
+            let mut users = self.users.lock().await;
+            let user = users.get_mut(user_id)
+                .ok_or_else(|| ApiError::not_found(format!("Unknown user: {}", user_id)))?;
+            if granted {
+                user.namespaces.insert(owner.to_string());
+            } else if !user.namespaces.remove(owner) {
+                return Err(ApiError::not_found(format!("User {} has no grant for {}'s namespace", user_id, owner)));
+            }
+        }
+        self.persist_state().await;
+        Ok(())
+    }
+
+    /// Which private namespaces a token reaches. Grants are looked up now,
+    /// so a subscription keeps the access it started with.
+    pub async fn access(&self, token: &AuthToken) -> Access {
+        let granted = self.users.lock().await
+            .get(&token.user_id)
+            .map(|user| user.namespaces.clone())
+            .unwrap_or_default();
+        Access::new(token.user_id.clone(), token.permissions.contains(&Permission::Admin), granted)
+    }
+
+    /// Removes a user together with every token issued to them and the
+    /// grants others had to their namespace.
+    pub async fn delete_user(&self, user_id: &str) -> bool {
+        let removed = {
+            let mut users = self.users.lock().await;
+            let removed = users.remove(user_id).is_some();
+            for user in users.values_mut() {
+                user.namespaces.remove(user_id);
+            }
+            removed
         };
+        if removed {
+            self.auth_tokens.lock().await.retain(|_, token| token.user_id != user_id);
+            self.default_tokens.lock().await.remove(user_id);
+            self.persist_state().await;
+        }
+        removed
+    }
+
+    pub async fn list_users(&self) -> Vec<UserInfo> {
+        let users = self.users.lock().await;
+        let mut list: Vec<UserInfo> = users.values()
+            .map(|user| UserInfo {
+                user_id: user.user_id.clone(),
+                permissions: user.permissions.clone(),
+                namespaces: user.namespaces.clone(),
+            })
+            .collect();
+        list.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+        list
+    }
+
+    pub async fn import_state(&self, snapshot: StateSnapshot) -> Result<(), ApiError> {
+        check_snapshot_users(&snapshot)?;
+        self.restore(snapshot).await;
+        self.persist_state().await;
+        Ok(())
+    }
+    
+    pub async fn authenticate(&self, token: &str, required_permission: Option<Permission>) -> bool {
+        self.authorize(Some(token), required_permission).await.is_ok()
+    }
+
+    /// Resolves a token and checks that it holds `required_permission` (or Admin).
+    pub async fn authorize(&self, token: Option<&str>, required_permission: Option<Permission>) -> Result<AuthToken, ApiError> {
+        let token = token.ok_or_else(|| ApiError::new(ErrorCode::Unauthorized, "Not authenticated"))?;
+        let auth_token = self.token_info(token).await.ok_or_else(ApiError::unauthorized)?;
+
+        match required_permission {
+            Some(required_perm) if !auth_token.permissions.contains(&required_perm)
+                && !auth_token.permissions.contains(&Permission::Admin) => Err(ApiError::unauthorized()),
+            _ => Ok(auth_token),
+        }
+    }
+
+    /// Like `authorize`, and also checks that the token's scope for the
+    /// permission covers the signal name and that the name is not in
+    /// someone else's private namespace.
+    pub async fn authorize_signal(&self, token: Option<&str>, permission: Permission, name: &str) -> Result<AuthToken, ApiError> {
+        let auth_token = self.authorize(token, Some(permission.clone())).await?;
+        if !auth_token.allows_signal(&permission, name) {
+            return Err(out_of_scope(&permission, name));
+        }
+        if !self.access(&auth_token).await.can_access(name) {
+            return Err(private_namespace(name));
+        }
+        Ok(auth_token)
+    }
+
+    /// Like `authorize`, and also checks that the token's scope for the
+    /// permission covers everything the pattern matches and that the pattern
+    /// does not name someone else's private namespace.
+    pub async fn authorize_pattern(&self, token: Option<&str>, permission: Permission, pattern: &Pattern) -> Result<AuthToken, ApiError> {
+        let auth_token = self.authorize(token, Some(permission.clone())).await?;
+        if !auth_token.allows_pattern(&permission, pattern) {
+            return Err(out_of_scope(&permission, pattern.as_str()));
+        }
+        if !self.access(&auth_token).await.can_access_pattern(pattern) {
+            return Err(private_namespace(pattern.as_str()));
+        }
+        Ok(auth_token)
+    }
+
+    pub async fn generate_token(&self, user_id: String, expires_in: Option<u64>) -> String {
+        let permissions = self.get_user_permissions(&user_id).await;
+        self.issue_token(user_id, permissions, HashMap::new(), expires_in).await
+    }
+
+    async fn issue_token(
+        &self,
+        user_id: String,
+        permissions: HashSet<Permission>,
+        scopes: HashMap<Permission, Vec<String>>,
+        expires_in: Option<u64>,
+    ) -> String {
+        let token = auth::random_string(32);
         
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_secs();
             
-        let expires_at = expires_in.map(|seconds| now + seconds);
+        let expires_at = expires_in.map(|seconds| now.saturating_add(seconds));
         
         let auth_token = AuthToken {
             token: token.clone(),
-            user_id: user_id.clone(),
-            permissions: self.get_user_permissions(&user_id).await,
+            id: auth::random_string(TOKEN_ID_LEN),
+            user_id,
+            permissions,
             created_at: now,
             expires_at,
+            scopes,
+            peer: false,
+            last_used: None,
         };
         
         let mut tokens = self.auth_tokens.lock().await;
         tokens.insert(token.clone(), auth_token);
+        drop(tokens);
+        self.persist_state().await;
         
         token
     }
@@ -174,75 +565,796 @@ impl DaemonState {
     }
 
     pub async fn revoke_token(&self, token_to_revoke: &str) -> bool {
+        let removed = self.auth_tokens.lock().await.remove(token_to_revoke).is_some();
+        if removed {
+            self.persist_state().await;
+        }
+        removed
+    }
+
+    pub async fn cleanup_expired_tokens(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let removed = {
+            let mut tokens = self.auth_tokens.lock().await;
+            let before = tokens.len();
+            tokens.retain(|_, token| token.expires_at.is_none_or(|expires_at| now <= expires_at));
+            before - tokens.len()
+        };
+
+        if removed > 0 {
+            println!("Removed {} expired tokens", removed);
+            self.persist_state().await;
+        }
+    }
+    
+    /// Issues a token for an existing user. When `requested` is given the token
+    /// only carries the requested permissions the user actually holds, and
+    /// `scopes` can limit permissions to signals matching some patterns.
+    pub async fn create_token(
+        &self,
+        user_id: &str,
+        requested: Option<HashSet<Permission>>,
+        scopes: HashMap<Permission, Vec<String>>,
+        expires_in: Option<u64>,
+    ) -> Result<String, ApiError> {
+        let user_permissions = self.users.lock().await
+            .get(user_id)
+            .map(|user| user.permissions.clone())
+            .ok_or_else(|| ApiError::not_found(format!("Unknown user: {}", user_id)))?;
+
+        let permissions = match requested {
+            Some(requested) => {
+                if let Some(missing) = requested.iter().find(|p| !user_permissions.contains(p)) {
+                    return Err(ApiError::invalid(format!("User {} does not hold permission {:?}", user_id, missing)));
+                }
+                requested
+            }
+            None => user_permissions,
+        };
+        if let Some(unheld) = scopes.keys().find(|p| !permissions.contains(p)) {
+            return Err(ApiError::invalid(format!("Token for {} would not hold scoped permission {:?}", user_id, unheld)));
+        }
+
+        Ok(self.issue_token(user_id.to_string(), permissions, scopes, expires_in).await)
+    }
+
+    /// Returns the token record if the token exists and has not expired,
+    /// and marks it used.
+    pub async fn token_info(&self, token: &str) -> Option<AuthToken> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
         let mut tokens = self.auth_tokens.lock().await;
-        tokens.remove(token_to_revoke).is_some()
+        let token = tokens
+            .get_mut(token)
+            .filter(|t| t.expires_at.is_none_or(|expires_at| now <= expires_at))?;
+        token.last_used = Some(now);
+        Some(token.clone())
+    }
+
+    pub async fn list_tokens(&self) -> Vec<TokenInfo> {
+        let mut list: Vec<TokenInfo> = self.auth_tokens.lock().await.values().map(TokenInfo::from).collect();
+        list.sort_by(|a, b| a.user_id.cmp(&b.user_id).then(a.created_at.cmp(&b.created_at)));
+        list
+    }
+
+    /// Pushes back the expiry of an expiring token, such as a login token,
+    /// to `expires_in` seconds from now. Refreshing cannot extend a token
+    /// further than a fresh login would.
+    pub async fn refresh_token(&self, token: &str, expires_in: Option<u64>) -> Result<TokenInfo, ApiError> {
+        let expires_in = expires_in.unwrap_or(LOGIN_TOKEN_TTL);
+        if expires_in > LOGIN_TOKEN_TTL {
+            return Err(ApiError::invalid(format!("Tokens can be refreshed for at most {} seconds", LOGIN_TOKEN_TTL)));
+        }
+        let expires_at = unix_now().checked_add(expires_in)
+            .ok_or_else(|| ApiError::invalid("Expiry out of range"))?;
+        let info = {
+            let mut tokens = self.auth_tokens.lock().await;
+            let auth_token = tokens.get_mut(token).ok_or_else(ApiError::unauthorized)?;
+            match auth_token.expires_at {
+                None => return Err(ApiError::invalid("Token does not expire")),
+                // Expired tokens linger until the next cleanup.
+                Some(previous) if previous < unix_now() => return Err(ApiError::unauthorized()),
+                Some(_) => {}
+            }
+            auth_token.expires_at = Some(expires_at);
+            TokenInfo::from(&*auth_token)
+        };
+        self.persist_state().await;
+        Ok(info)
+    }
+
+    /// Revokes every token of a user, returning how many there were.
+    pub async fn revoke_user_tokens(&self, user_id: &str) -> usize {
+        let removed = {
+            let mut tokens = self.auth_tokens.lock().await;
+            let before = tokens.len();
+            tokens.retain(|_, token| token.user_id != user_id);
+            before - tokens.len()
+        };
+        if removed > 0 {
+            self.persist_state().await;
+        }
+        removed
+    }
+
+    pub async fn revoke_token_id(&self, id: &str) -> bool {
+        let removed = {
+            let mut tokens = self.auth_tokens.lock().await;
+            let before = tokens.len();
+            tokens.retain(|_, token| token.id != id);
+            before != tokens.len()
+        };
+        if removed {
+            self.persist_state().await;
+        }
+        removed
     }
     
+    /// Issues a token for a connection whose Unix account is mapped to a
+    /// SignalBus user by the peer auth config. Returns `None` when peer auth
+    /// is off or the account is not mapped to an existing user.
+    pub async fn peer_token(&self, peer: UCred) -> Option<String> {
+        let mapping = self.peer_auth.as_ref()?.resolve(&peer).await?;
+        let mut permissions = self.users.lock().await.get(&mapping.user)?.permissions.clone();
+        if let Some(allowed) = &mapping.permissions {
+            permissions.retain(|permission| allowed.contains(permission));
+        }
+
+        let token = auth::random_string(32);
+        let auth_token = AuthToken {
+            token: token.clone(),
+            id: auth::random_string(TOKEN_ID_LEN),
+            user_id: mapping.user.clone(),
+            permissions,
+            created_at: unix_now(),
+            expires_at: None,
+            scopes: HashMap::new(),
+            peer: true,
+            last_used: None,
+        };
+        self.auth_tokens.lock().await.insert(token.clone(), auth_token);
+        println!("Authenticated uid {} as {} by peer credentials", peer.uid(), mapping.user);
+        Some(token)
+    }
+
+    /// Drops the token of a peer-authenticated connection once it closes.
+    pub async fn release_peer_token(&self, token: &str) {
+        self.auth_tokens.lock().await.remove(token);
+    }
+
     pub async fn login(&self, user_id: &str, password: &str) -> Option<String> {
         let maybe_user = {
             let users = self.users.lock().await;
             users.get(user_id).cloned()
         };
 
-        if let Some(user) = maybe_user {
-            if user.password_hash == password {
-                return Some(self.generate_token(user_id.to_string(), Some(3600)).await);
-            }
+        if let Some(user) = maybe_user
+            && auth::verify_password(password, &user.password_hash)
+        {
+            return Some(self.generate_token(user_id.to_string(), Some(LOGIN_TOKEN_TTL)).await);
         }
         None
     }
 
-    pub async fn subscribe(&self, pattern: String, tx: Sender<Signal>) {
+    /// Registers a subscriber; the handle's id is used to unsubscribe it.
+    ///
+    /// With replay options the matching history is captured while the
+    /// subscribers lock is held. `publish` appends to history before looking
+    /// up subscribers, so every signal is either in the capture or delivered
+    /// live, and `seen` tells the two apart.
+    pub async fn subscribe(&self, pattern: Pattern, options: &ListenOptions, access: Access) -> Result<SubscriberHandle, ApiError> {
+        if let Some(group) = &options.group {
+            check_name("consumer group", group)?;
+            if options.replays() {
+                return Err(ApiError::invalid("Consumer group members cannot replay history"));
+            }
+        }
+
+        let (tx, rx) = queue::bounded(SUBSCRIBER_QUEUE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let subscriber = Arc::new(Subscriber {
+            tx,
+            group: options.group.clone(),
+            access: access.clone(),
+            policy: options.overflow,
+            block_timeout: options.block_timeout_ms.map(Duration::from_millis).unwrap_or(DEFAULT_BLOCK_TIMEOUT),
+            dropped: dropped.clone(),
+        });
+
+        let id = self.next_subscription.fetch_add(1, Ordering::Relaxed);
         let mut subs = self.subscribers.lock().await;
-        subs.entry(pattern.clone()).or_insert_with(Vec::new).push(tx);
-        println!("New subscriber for pattern: {}", pattern);
+        if let Some(name) = &options.group {
+            let mut groups = self.groups.lock().await;
+            let group = groups.entry(name.clone()).or_insert_with(|| ConsumerGroup {
+                balance: options.balance.unwrap_or_default(),
+                members: 0,
+                next: 0,
+            });
+            if options.balance.is_some_and(|balance| balance != group.balance) {
+                return Err(ApiError::invalid(format!(
+                    "Consumer group '{}' already balances {:?}", name, group.balance
+                )));
+            }
+            group.members += 1;
+        }
+        subs.insert(&pattern, id, subscriber);
+        println!("New subscriber {} for pattern: {} ({:?})", id, pattern, options.overflow);
+
+        let (replay, seen) = if options.replays() {
+            let history = self.signal_history.lock().await;
+            let seen = history.iter().map(|ps| ps.id).collect();
+            let mut replay = select_replay(&history, &pattern, options);
+            replay.retain(|ps| access.can_access(&ps.signal.name));
+            (replay, seen)
+        } else {
+            (Vec::new(), HashSet::new())
+        };
+        drop(subs);
+
+        Ok(SubscriberHandle { id, rx, dropped, replay, seen })
     }
 
-    pub async fn publish(&self, signal: Signal, ttl: Option<u64>) -> Result<()> {
-        if !self.check_rate_limit(&signal.name).await {
-            return Err(anyhow::anyhow!(
-                "Rate limit exceeded for signal: {}", 
-                signal.name
+    pub async fn unsubscribe(&self, id: u64) -> bool {
+        let subscriber = self.subscribers.lock().await.remove(id);
+        let removed = match subscriber {
+            Some(subscriber) => {
+                self.leave_groups(subscriber.group.clone()).await;
+                true
+            }
+            None => self.durables.lock().await.detach(id),
+        };
+        if removed {
+            println!("Subscriber {} removed", id);
+        }
+        removed
+    }
+
+    /// Attaches a consumer to a durable subscription, creating it on first
+    /// use. A new subscription starts after the most recent signal.
+    pub async fn attach_durable(&self, name: &str, pattern: &Pattern, owner: &AuthToken) -> Result<(u64, Attachment), ApiError> {
+        check_name("durable subscription", name)?;
+
+        let id = self.next_subscription.fetch_add(1, Ordering::Relaxed);
+        let admin = owner.permissions.contains(&Permission::Admin);
+        let mut durables = self.durables.lock().await;
+        // Ids are only handed out under the durables lock, see `add_to_history`.
+        let start = self.next_id.load(Ordering::SeqCst) - 1;
+        let attachment = durables.attach(name, pattern, &owner.user_id, admin, id, start)?;
+        println!("Durable subscription '{}' attached as {} at cursor {}", name, id, attachment.cursor);
+        Ok((id, attachment))
+    }
+
+    /// The next signal after `after` for an attached durable consumer.
+    pub async fn next_durable(&self, subscription: u64, after: u64) -> Result<Option<PersistentSignal>, Detached> {
+        self.durables.lock().await.next_after(subscription, after)
+    }
+
+    pub async fn ack_durable(&self, subscription: u64, id: u64) {
+        self.durables.lock().await.ack(subscription, id);
+    }
+
+    /// Durable subscriptions visible to the caller: all of them for admins,
+    /// otherwise the caller's own.
+    pub async fn list_durables(&self, caller: &AuthToken) -> Vec<DurableInfo> {
+        let admin = caller.permissions.contains(&Permission::Admin);
+        self.durables.lock().await
+            .list()
+            .into_iter()
+            .filter(|durable| admin || durable.user_id == caller.user_id)
+            .collect()
+    }
+
+    pub async fn delete_durable(&self, name: &str, caller: &AuthToken) -> Result<(), ApiError> {
+        let admin = caller.permissions.contains(&Permission::Admin);
+        {
+            let mut durables = self.durables.lock().await;
+            match durables.owner(name) {
+                None => return Err(ApiError::not_found(format!("Unknown durable subscription: {}", name))),
+                Some(owner) if owner != caller.user_id && !admin => return Err(ApiError::unauthorized()),
+                Some(_) => durables.remove(name),
+            };
+        }
+        self.persist_state().await;
+        Ok(())
+    }
+
+    /// Persists durable cursors if any moved since the last call.
+    pub async fn flush_durables(&self) {
+        let dirty = self.durables.lock().await.take_dirty();
+        if dirty {
+            self.persist_state().await;
+        }
+    }
+
+    /// Moves a signal a consumer kept failing on to the dead-letter queue.
+    pub async fn dead_letter(&self, dead_letter: DeadLetter) {
+        let mut dead_letters = self.dead_letters.lock().await;
+        let (signal_id, failures) = (dead_letter.signal.id, dead_letter.failures);
+        let id = dead_letters.push(dead_letter);
+        println!("Signal {} dead-lettered as {} after {} failures", signal_id, id, failures);
+        if let Err(e) = dead_letters.save().await {
+            eprintln!("Failed to persist dead letters: {}", e);
+        }
+    }
+
+    pub async fn list_dead_letters(&self) -> Vec<DeadLetter> {
+        self.dead_letters.lock().await.list()
+    }
+
+    pub async fn get_dead_letter(&self, id: u64) -> Option<DeadLetter> {
+        self.dead_letters.lock().await.get(id).cloned()
+    }
+
+    /// Emits dead letters again, one or all, and returns how many were replayed.
+    pub async fn replay_dead_letters(&self, id: Option<u64>, caller: &AuthToken) -> Result<usize, ApiError> {
+        let access = self.access(caller).await;
+        let in_scope = |dead_letter: &DeadLetter| {
+            let name = &dead_letter.signal.signal.name;
+            caller.allows_signal(&Permission::Write, name) && access.can_access(name)
+        };
+        // Taken out so concurrent replays cannot publish them twice; the file
+        // keeps them until they were published.
+        let selected: Vec<DeadLetter> = {
+            let mut dead_letters = self.dead_letters.lock().await;
+            let selected: Vec<DeadLetter> = match id {
+                Some(id) => {
+                    let dead_letter = dead_letters.get(id).cloned().ok_or_else(|| unknown_dead_letter(id))?;
+                    if !in_scope(&dead_letter) {
+                        return Err(out_of_scope(&Permission::Write, &dead_letter.signal.signal.name));
+                    }
+                    vec![dead_letter]
+                }
+                None => dead_letters.list().into_iter().filter(in_scope).collect(),
+            };
+            for dead_letter in &selected {
+                dead_letters.remove(dead_letter.id);
+            }
+            selected
+        };
+
+        let mut replayed = 0;
+        let mut result = Ok(());
+        let mut remaining = selected.into_iter().peekable();
+        while let Some(dead_letter) = remaining.peek() {
+            let PersistentSignal { signal, ttl, .. } = dead_letter.signal.clone();
+            let exempt = signal.priority == Priority::Critical && self.sender_bypasses_rate_limits(&signal).await;
+            if let Err(e) = self.publish_signal(signal, ttl, exempt).await {
+                result = Err(e);
+                break;
+            }
+            remaining.next();
+            replayed += 1;
+        }
+
+        let mut dead_letters = self.dead_letters.lock().await;
+        dead_letters.restore(remaining.collect());
+        if replayed > 0
+            && let Err(e) = dead_letters.save().await
+        {
+            eprintln!("Failed to persist dead letters: {}", e);
+        }
+        result.map(|()| replayed)
+    }
+
+    /// Whether the signal's original emitter may let it skip rate limits:
+    /// its token still does, or the daemon emitted it, as for cron jobs.
+    async fn sender_bypasses_rate_limits(&self, signal: &Signal) -> bool {
+        let Some(sender) = &signal.sender else {
+            return false;
+        };
+        let Some(token_id) = &sender.token_id else {
+            return true;
+        };
+        let now = unix_now();
+        self.auth_tokens.lock().await.values().any(|token| {
+            token.id == *token_id
+                && token.expires_at.is_none_or(|expires_at| now <= expires_at)
+                && bypasses_rate_limits(token)
+        })
+    }
+
+    /// Deletes one dead letter or all of them and returns how many were removed.
+    pub async fn purge_dead_letters(&self, id: Option<u64>) -> Result<usize, ApiError> {
+        let mut dead_letters = self.dead_letters.lock().await;
+        let purged = match id {
+            Some(id) => dead_letters.remove(id).map(|_| 1).ok_or_else(|| unknown_dead_letter(id))?,
+            None => dead_letters.clear(),
+        };
+        if let Err(e) = dead_letters.save().await {
+            eprintln!("Failed to persist dead letters: {}", e);
+        }
+        Ok(purged)
+    }
+
+    /// Holds a signal until `due_at`, then publishes it like an emit at that time.
+    pub async fn schedule_signal(
+        &self,
+        signal: Signal,
+        ttl: Option<u64>,
+        due_at: u64,
+        emitter: &AuthToken,
+    ) -> Result<ScheduledSignal, ApiError> {
+        if due_at > unix_now().saturating_add(MAX_SCHEDULE_AHEAD) {
+            return Err(ApiError::invalid("Signals can be scheduled at most ten years ahead"));
+        }
+        let mut schedule = self.schedule.lock().await;
+        if schedule.is_full() {
+            return Err(ApiError::invalid("Too many scheduled signals"));
+        }
+        let scheduled = schedule.push(ScheduledSignal {
+            id: 0,
+            bypass_rate_limits: signal.priority == Priority::Critical && bypasses_rate_limits(emitter),
+            signal,
+            ttl,
+            due_at,
+            user_id: emitter.user_id.clone(),
+            scheduled_at: unix_now(),
+        });
+        schedule.save().await.map_err(ApiError::internal)?;
+        println!("Signal {} scheduled as {} for {}", scheduled.signal.name, scheduled.id, due_at);
+        drop(schedule);
+
+        self.schedule_changed.notify_one();
+        Ok(scheduled)
+    }
+
+    pub async fn list_scheduled(&self, caller: &AuthToken) -> Vec<ScheduledSignal> {
+        let admin = caller.permissions.contains(&Permission::Admin);
+        self.schedule.lock().await
+            .list()
+            .into_iter()
+            .filter(|scheduled| admin || scheduled.user_id == caller.user_id)
+            .collect()
+    }
+
+    pub async fn cancel_scheduled(&self, id: u64, caller: &AuthToken) -> Result<(), ApiError> {
+        let admin = caller.permissions.contains(&Permission::Admin);
+        let mut schedule = self.schedule.lock().await;
+        match schedule.get(id) {
+            None => return Err(ApiError::not_found(format!("Unknown scheduled signal: {}", id))),
+            Some(scheduled) if scheduled.user_id != caller.user_id && !admin => return Err(ApiError::unauthorized()),
+            Some(_) => schedule.remove(id),
+        };
+        schedule.save().await.map_err(ApiError::internal)?;
+        drop(schedule);
+
+        self.schedule_changed.notify_one();
+        Ok(())
+    }
+
+    /// Publishes every scheduled signal that is due and returns when the next
+    /// one is. A signal leaves the schedule only after it was published, so
+    /// a crash in between publishes it again rather than losing it. Signals
+    /// that could not be published, e.g. because of a rate limit, are
+    /// retried a few seconds later.
+    async fn publish_due_signals(&self) -> Option<u64> {
+        let now = unix_now();
+        let due = self.schedule.lock().await.due(now);
+
+        let mut failed = HashSet::new();
+        for scheduled in &due {
+            let mut signal = scheduled.signal.clone();
+            signal.timestamp = now;
+            if let Err(e) = self.publish_signal(signal, scheduled.ttl, scheduled.bypass_rate_limits).await {
+                eprintln!("Scheduled signal {} ({}) was not published, retrying in {}s: {}",
+                          scheduled.id, scheduled.signal.name, SCHEDULE_RETRY, e);
+                failed.insert(scheduled.id);
+            }
+        }
+
+        let mut schedule = self.schedule.lock().await;
+        if !due.is_empty() {
+            for scheduled in &due {
+                if failed.contains(&scheduled.id) {
+                    schedule.postpone(scheduled.id, now.saturating_add(SCHEDULE_RETRY));
+                } else {
+                    schedule.remove(scheduled.id);
+                }
+            }
+            if let Err(e) = schedule.save().await {
+                eprintln!("Failed to persist the schedule: {}", e);
+            }
+        }
+        schedule.next_due()
+    }
+
+    pub async fn add_cron_job(&self, spec: CronSpec, user_id: &str) -> Result<CronJob, ApiError> {
+        let next_run = cron::next_run(&spec.schedule, unix_now()).map_err(ApiError::invalid)?;
+        let mut cron_jobs = self.cron_jobs.lock().await;
+        let job = cron_jobs.push(CronJob {
+            id: 0,
+            spec,
+            paused: false,
+            user_id: user_id.to_string(),
+            next_run,
+            last_run: None,
+        });
+        cron_jobs.save().await.map_err(ApiError::internal)?;
+        println!("Cron job {} publishes {} on '{}'", job.id, job.spec.signal, job.spec.schedule);
+        drop(cron_jobs);
+
+        self.cron_changed.notify_one();
+        Ok(job)
+    }
+
+    pub async fn list_cron_jobs(&self) -> Vec<CronJob> {
+        self.cron_jobs.lock().await.list()
+    }
+
+    pub async fn remove_cron_job(&self, id: u64) -> Result<(), ApiError> {
+        let mut cron_jobs = self.cron_jobs.lock().await;
+        cron_jobs.remove(id).ok_or_else(|| unknown_cron_job(id))?;
+        cron_jobs.save().await.map_err(ApiError::internal)?;
+        drop(cron_jobs);
+
+        self.cron_changed.notify_one();
+        Ok(())
+    }
+
+    /// Pauses or resumes a job. Runs that fall in a pause are not missed
+    /// runs: a resumed job waits for its next scheduled time.
+    pub async fn set_cron_job_paused(&self, id: u64, paused: bool) -> Result<(), ApiError> {
+        let mut cron_jobs = self.cron_jobs.lock().await;
+        let job = cron_jobs.get_mut(id).ok_or_else(|| unknown_cron_job(id))?;
+        if job.paused == paused {
+            return Ok(());
+        }
+        if !paused {
+            job.next_run = cron::next_run(&job.spec.schedule, unix_now()).map_err(ApiError::invalid)?;
+        }
+        job.paused = paused;
+        cron_jobs.save().await.map_err(ApiError::internal)?;
+        drop(cron_jobs);
+
+        self.cron_changed.notify_one();
+        Ok(())
+    }
+
+    /// Publishes the cron jobs that are due and returns when the next one is.
+    ///
+    /// A run more than `CRON_GRACE` late was missed; depending on the job it
+    /// is published once now or skipped. Either way the job continues at
+    /// its next scheduled time after now.
+    async fn run_due_cron_jobs(&self) -> Option<u64> {
+        let now = unix_now();
+        let due = self.cron_jobs.lock().await.due(now);
+
+        let mut published = Vec::new();
+        for job in &due {
+            let missed = now.saturating_sub(job.next_run) > CRON_GRACE.as_secs();
+            if missed && job.spec.missed == MissedRuns::Skip {
+                println!("Cron job {} skipped the run it missed at {}", job.id, job.next_run);
+                continue;
+            }
+            if missed {
+                println!("Cron job {} catching up on the run it missed at {}", job.id, job.next_run);
+            }
+
+            let spec = &job.spec;
+            let signal = Signal {
+                name: spec.signal.clone(),
+                payload: spec.payload.clone(),
+                timestamp: now,
+                reply_to: None,
+                correlation_id: None,
+                priority: spec.priority,
+                sender: Some(Sender { user_id: job.user_id.clone(), token_id: None, pid: None, uid: None }),
+            };
+            // Only admins register jobs, so critical ones may skip rate limits.
+            match self.publish_signal(signal, spec.ttl, spec.priority == Priority::Critical).await {
+                Ok(()) => published.push(job.id),
+                Err(e) => eprintln!("Cron job {} ({}) was not published: {}", job.id, spec.signal, e),
+            }
+        }
+
+        let mut cron_jobs = self.cron_jobs.lock().await;
+        if !due.is_empty() {
+            for job in &due {
+                let Some(entry) = cron_jobs.get_mut(job.id) else { continue };
+                if published.contains(&job.id) {
+                    entry.last_run = Some(now);
+                }
+                match cron::next_run(&entry.spec.schedule, now) {
+                    Ok(next_run) => entry.next_run = next_run,
+                    Err(e) => {
+                        eprintln!("Pausing cron job {}: {}", entry.id, e);
+                        entry.paused = true;
+                    }
+                }
+            }
+            if let Err(e) = cron_jobs.save().await {
+                eprintln!("Failed to persist cron jobs: {}", e);
+            }
+        }
+        cron_jobs.next_due()
+    }
+
+    /// Drops subscribers whose receiving side has gone away.
+    pub async fn cleanup_subscribers(&self) {
+        let mut subs = self.subscribers.lock().await;
+        let mut left = Vec::new();
+        let removed = subs.retain(|subscriber| {
+            let closed = subscriber.tx.is_closed();
+            if closed {
+                left.extend(subscriber.group.clone());
+            }
+            !closed
+        });
+        if !removed.is_empty() {
+            println!("Removed {} dead subscribers, {} remaining", removed.len(), subs.len());
+        }
+        drop(subs);
+        self.leave_groups(left).await;
+    }
+
+    /// Forgets one member of each named group, dropping groups left empty.
+    async fn leave_groups(&self, names: impl IntoIterator<Item = String>) {
+        let mut groups = self.groups.lock().await;
+        for name in names {
+            if let Some(group) = groups.get_mut(&name) {
+                group.members -= 1;
+                if group.members == 0 {
+                    groups.remove(&name);
+                }
+            }
+        }
+    }
+
+    /// Keeps ungrouped targets and one member of every consumer group among them.
+    async fn balance_groups(&self, targets: Vec<(u64, Arc<Subscriber>)>) -> Vec<(u64, Arc<Subscriber>)> {
+        if targets.iter().all(|(_, subscriber)| subscriber.group.is_none()) {
+            return targets;
+        }
+
+        let mut selected = Vec::with_capacity(targets.len());
+        let mut members: HashMap<String, Vec<(u64, Arc<Subscriber>)>> = HashMap::new();
+        for (id, subscriber) in targets {
+            match &subscriber.group {
+                Some(group) => members.entry(group.clone()).or_default().push((id, subscriber)),
+                None => selected.push((id, subscriber)),
+            }
+        }
+
+        let mut groups = self.groups.lock().await;
+        for (name, mut members) in members {
+            members.sort_by_key(|(id, _)| *id);
+            let (balance, start) = match groups.get_mut(&name) {
+                Some(group) => {
+                    group.next = group.next.wrapping_add(1);
+                    (group.balance, group.next % members.len())
+                }
+                None => (Balance::default(), 0),
+            };
+            // Ties go to the member whose turn it is.
+            let mut rotation = members.iter().cycle().skip(start).take(members.len());
+            let chosen = match balance {
+                Balance::RoundRobin => rotation.next(),
+                Balance::LeastBusy => rotation.min_by_key(|(_, subscriber)| subscriber.tx.len()),
+            };
+            selected.extend(chosen.cloned());
+        }
+        selected
+    }
+
+    /// Records and delivers a signal. Critical signals skip the rate limits
+    /// when the emitter is allowed to manage them.
+    pub async fn publish(&self, signal: Signal, ttl: Option<u64>, emitter: Option<&AuthToken>) -> Result<(), ApiError> {
+        let exempt = signal.priority == Priority::Critical && emitter.is_some_and(bypasses_rate_limits);
+        self.publish_signal(signal, ttl, exempt).await
+    }
+
+    async fn publish_signal(&self, signal: Signal, ttl: Option<u64>, exempt: bool) -> Result<(), ApiError> {
+        if !exempt && !self.check_rate_limit(&signal.name).await {
+            return Err(ApiError::new(
+                ErrorCode::RateLimited,
+                format!("Rate limit exceeded for signal: {}", signal.name),
             ));
         }
 
-        self.add_to_history(signal.clone(), ttl).await;
+        let signal = self.add_to_history(signal, ttl).await.map_err(ApiError::internal)?;
         
-        let subs = self.subscribers.lock().await;
+        // Deliver outside the lock so a slow subscriber never stalls other emitters.
+        let targets: Vec<(u64, Arc<Subscriber>)> = self.subscribers.lock().await
+            .matches(&signal.signal.name)
+            .into_iter()
+            .filter(|(_, subscriber)| subscriber.access.can_access(&signal.signal.name))
+            .map(|(id, subscriber)| (id, subscriber.clone()))
+            .collect();
+        let targets = self.balance_groups(targets).await;
+
         let mut matched = 0;
+        let mut dead = Vec::new();
+        let mut blocked = Vec::new();
         
-        for (pattern, clients) in subs.iter() {
-            if pattern_match(pattern, &signal.name) {
-                matched += clients.len();
-                for client in clients {
-                    let _ = client.send(signal.clone()).await;
-                }
+        for (id, subscriber) in &targets {
+            match deliver(*id, subscriber, signal.clone()) {
+                Some(Delivery::Delivered) => matched += 1,
+                Some(Delivery::Dropped) => {}
+                Some(Delivery::Closed) => dead.push(*id),
+                None => blocked.push((*id, subscriber)),
             }
         }
+
+        // Blocking subscribers wait side by side, so the emitter waits at most one timeout.
+        let waits = blocked.into_iter().map(|(id, subscriber)| {
+            let signal = signal.clone();
+            async move {
+                let delivery = match tokio::time::timeout(subscriber.block_timeout, subscriber.tx.send(signal)).await {
+                    Ok(Ok(())) => Delivery::Delivered,
+                    Ok(Err(_)) => Delivery::Closed,
+                    Err(_) => {
+                        subscriber.dropped.fetch_add(1, Ordering::Relaxed);
+                        Delivery::Dropped
+                    }
+                };
+                (id, delivery)
+            }
+        });
+        for (id, delivery) in futures::future::join_all(waits).await {
+            match delivery {
+                Delivery::Delivered => matched += 1,
+                Delivery::Dropped => {}
+                Delivery::Closed => dead.push(id),
+            }
+        }
+
+        if !dead.is_empty() {
+            let mut subs = self.subscribers.lock().await;
+            let left: Vec<String> = dead.into_iter()
+                .filter_map(|id| subs.remove(id))
+                .filter_map(|subscriber| subscriber.group.clone())
+                .collect();
+            drop(subs);
+            self.leave_groups(left).await;
+        }
         
-        println!("Published signal '{}' to {} clients (TTL: {:?})", signal.name, matched, ttl);
+        println!("Published signal '{}' to {} clients (TTL: {:?})", signal.signal.name, matched, ttl);
         Ok(())
     }
 
-    pub async fn add_to_history(&self, signal: Signal, ttl: Option<u64>) -> u64 {
-        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
-        let persistent_signal = PersistentSignal { signal, id, ttl };
-        
+    pub async fn add_to_history(&self, signal: Signal, ttl: Option<u64>) -> Result<PersistentSignal> {
+        // Durable consumers read in id order, so ids are assigned and buffered
+        // under the durables lock; otherwise a consumer could move past an id
+        // whose signal is still on its way.
+        let mut durables = self.durables.lock().await;
+        let persistent_signal = match &self.wal {
+            Some(wal) => {
+                // Ids are assigned under the WAL lock so the log stays in id order.
+                let mut wal = wal.lock().await;
+                let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+                let persistent_signal = PersistentSignal { signal, id, ttl };
+                wal.append(&persistent_signal).await?;
+                persistent_signal
+            }
+            None => {
+                let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+                PersistentSignal { signal, id, ttl }
+            }
+        };
+        durables.offer(&persistent_signal);
+        drop(durables);
+
         let mut history = self.signal_history.lock().await;
-        history.push_back(persistent_signal);
+        history.push_back(persistent_signal.clone());
         
         while history.len() > self.max_history_size {
             history.pop_front();
         }
         
-        id
+        Ok(persistent_signal)
     }
     
-    pub async fn get_recent_signals(&self, pattern: &str, limit: usize) -> Vec<PersistentSignal> {
+    pub async fn get_recent_signals(&self, pattern: &Pattern, limit: usize, access: &Access) -> Vec<PersistentSignal> {
         let history = self.signal_history.lock().await;
         history.iter()
             .rev()
-            .filter(|ps| pattern_match(pattern, &ps.signal.name))
+            .filter(|ps| pattern.matches(&ps.signal.name) && access.can_access(&ps.signal.name))
             .take(limit)
             .cloned()
             .collect()
@@ -254,88 +1366,326 @@ impl DaemonState {
             .unwrap()
             .as_secs();
             
-        let mut history = self.signal_history.lock().await;
-        history.retain(|ps| {
-            if let Some(ttl) = ps.ttl {
-                ps.signal.timestamp + ttl > now
-            } else {
-                true 
+        let live: HashSet<u64> = {
+            let mut history = self.signal_history.lock().await;
+            history.retain(|ps| ps.is_live(now));
+            
+            println!("Cleanup completed, {} signals in history", history.len());
+            history.iter().map(|ps| ps.id).collect()
+        };
+        let live: HashSet<u64> = {
+            let mut durables = self.durables.lock().await;
+            durables.expire(now);
+            live.into_iter().chain(durables.pending_ids()).collect()
+        };
+
+        if let Some(wal) = &self.wal
+            && let Err(e) = wal.lock().await.compact(&live).await
+        {
+            eprintln!("WAL compaction error: {}", e);
+        }
+    }
+
+    pub async fn sync_wal(&self) {
+        if let Some(wal) = &self.wal {
+            let mut wal = wal.lock().await;
+            if wal.policy() == FsyncPolicy::Periodic
+                && let Err(e) = wal.sync().await
+            {
+                eprintln!("WAL sync error: {}", e);
             }
-        });
-        
-        println!("Cleanup completed, {} signals in history", history.len());
+        }
     }
 
-    pub async fn set_rate_limit(&self, pattern: String, max_signals: u32, time_window_secs: u64) {
+    pub async fn set_rate_limit(&self, pattern: Pattern, max_signals: u32, time_window_secs: u64) {
         let rule = RateLimitRule {
             max_signals,
             time_window: Duration::from_secs(time_window_secs),
         };
         
         let mut limits = self.rate_limits.lock().await;
-        limits.insert(pattern.clone(), rule);
         println!("Rate limit set: {} signals per {} seconds for pattern '{}'", 
                  max_signals, time_window_secs, pattern);
+        limits.insert(pattern, rule);
+        drop(limits);
+        self.persist_state().await;
+    }
+
+    pub async fn list_rate_limits(&self) -> Vec<RateLimitInfo> {
+        let limits = self.rate_limits.lock().await;
+        let mut list: Vec<RateLimitInfo> = limits.iter()
+            .map(|(pattern, rule)| RateLimitInfo {
+                pattern: pattern.to_string(),
+                max_signals: rule.max_signals,
+                per_seconds: rule.time_window.as_secs(),
+            })
+            .collect();
+        list.sort_by(|a, b| a.pattern.cmp(&b.pattern));
+        list
+    }
+
+    pub async fn check_rate_limit(&self, signal_name: &str) -> bool {
+        let limits = self.rate_limits.lock().await;
+        let mut counters = self.signal_counters.lock().await;
+        
+        let now = Instant::now();
+        
+        for (pattern, rule) in limits.iter() {
+            if pattern.matches(signal_name) {
+                let counter = counters.entry(pattern.to_string()).or_insert_with(VecDeque::new);
+                
+                while let Some(front) = counter.front() {
+                    if now.duration_since(*front) > rule.time_window {
+                        counter.pop_front();
+                    } else {
+                        break;
+                    }
+                }
+                
+                if counter.len() >= rule.max_signals as usize {
+                    println!("Rate limit exceeded for pattern '{}': {} signals in {} seconds", 
+                             pattern, counter.len(), rule.time_window.as_secs());
+                    return false;
+                }
+                
+                counter.push_back(now);
+                return true;
+            }
+        }
+        
+        true
+    }
+
+    pub async fn cleanup_rate_limit_counters(&self) {
+        let limits = self.rate_limits.lock().await;
+        let mut counters = self.signal_counters.lock().await;
+        let now = Instant::now();
+        
+        for (pattern, rule) in limits.iter() {
+            if let Some(counter) = counters.get_mut(pattern.as_str()) {
+                counter.retain(|&timestamp| now.duration_since(timestamp) <= rule.time_window);
+            }
+        }
+    }
+}
+
+/// Live history entries matching a listen request's replay options, oldest first.
+fn select_replay(history: &VecDeque<PersistentSignal>, pattern: &Pattern, options: &ListenOptions) -> Vec<PersistentSignal> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let mut replay: Vec<PersistentSignal> = history.iter()
+        .filter(|ps| ps.is_live(now))
+        .filter(|ps| options.from_id.is_none_or(|from_id| ps.id >= from_id))
+        .filter(|ps| options.since.is_none_or(|since| ps.signal.timestamp >= since))
+        .filter(|ps| pattern.matches(&ps.signal.name))
+        .cloned()
+        .collect();
+    replay.sort_by_key(|ps| ps.id);
+
+    if let Some(last) = options.last
+        && replay.len() > last
+    {
+        replay.drain(..replay.len() - last);
+    }
+    replay
+}
+
+/// Names of durable subscriptions and consumer groups travel in line-based
+/// output, so whitespace, control characters and `|` are rejected.
+fn check_name(kind: &str, name: &str) -> Result<(), ApiError> {
+    if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c.is_control() || c == '|') {
+        return Err(ApiError::invalid(format!("Invalid {} name: {:?}", kind, name)));
+    }
+    Ok(())
+}
+
+/// User ids name private namespaces (`user.<id>.*`), so they cannot
+/// contain separators, and travel in line-based output like other names.
+fn check_user_id(user_id: &str) -> Result<(), ApiError> {
+    if user_id.is_empty() || user_id.contains(|c: char| SEPARATORS.contains(&c) || c.is_whitespace() || c.is_control() || c == '|') {
+        return Err(ApiError::invalid(format!("Invalid user id: {:?}", user_id)));
+    }
+    Ok(())
+}
+
+/// Rejects a snapshot with user ids `check_user_id` would refuse, e.g. one
+/// saved before ids were checked. They have to be renamed by hand.
+fn check_snapshot_users(snapshot: &StateSnapshot) -> Result<(), ApiError> {
+    let invalid: Vec<String> = snapshot.users.iter()
+        .filter(|user| check_user_id(&user.user_id).is_err())
+        .map(|user| format!("{:?}", user.user_id))
+        .collect();
+    if !invalid.is_empty() {
+        return Err(ApiError::invalid(format!(
+            "Invalid user ids in state: {}. User ids cannot contain '.', ':', whitespace or '|'; rename them in the state file",
+            invalid.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+fn unknown_dead_letter(id: u64) -> ApiError {
+    ApiError::not_found(format!("Unknown dead letter: {}", id))
+}
+
+fn out_of_scope(permission: &Permission, name: &str) -> ApiError {
+    ApiError::new(ErrorCode::Unauthorized, format!("Token's {:?} scope does not cover '{}'", permission, name))
+}
+
+fn private_namespace(name: &str) -> ApiError {
+    ApiError::new(ErrorCode::Unauthorized, format!("'{}' is in another user's private namespace", name))
+}
+
+fn unknown_cron_job(id: u64) -> ApiError {
+    ApiError::not_found(format!("Unknown cron job: {}", id))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// How long until a unix time, zero once it has passed. Times the system
+/// clock cannot represent are treated as never due.
+fn time_until(timestamp: u64) -> Duration {
+    match UNIX_EPOCH.checked_add(Duration::from_secs(timestamp)) {
+        Some(time) => time.duration_since(SystemTime::now()).unwrap_or_default(),
+        None => Duration::MAX,
     }
+}
 
-    pub async fn check_rate_limit(&self, signal_name: &str) -> bool {
-        let limits = self.rate_limits.lock().await;
-        let mut counters = self.signal_counters.lock().await;
-        
-        let now = Instant::now();
-        
-        for (pattern, rule) in limits.iter() {
-            if pattern_match(pattern, signal_name) {
-                let counter = counters.entry(pattern.clone()).or_insert_with(VecDeque::new);
-                
-                while let Some(front) = counter.front() {
-                    if now.duration_since(*front) > rule.time_window {
-                        counter.pop_front();
-                    } else {
-                        break;
-                    }
-                }
-                
-                if counter.len() >= rule.max_signals as usize {
-                    println!("Rate limit exceeded for pattern '{}': {} signals in {} seconds", 
-                             pattern, counter.len(), rule.time_window.as_secs());
-                    return false;
+/// Whether the token may send critical signals past the rate limits.
+fn bypasses_rate_limits(token: &AuthToken) -> bool {
+    token.permissions.contains(&Permission::RateLimit) || token.permissions.contains(&Permission::Admin)
+}
+
+/// Tries to queue a signal without waiting. `None` means the queue is full and
+/// the subscriber's policy is to block.
+fn deliver(id: u64, subscriber: &Subscriber, signal: PersistentSignal) -> Option<Delivery> {
+    let signal = match subscriber.tx.try_send(signal) {
+        Ok(()) => return Some(Delivery::Delivered),
+        Err(TrySendError::Closed) => return Some(Delivery::Closed),
+        Err(TrySendError::Full(signal)) => *signal,
+    };
+
+    match subscriber.policy {
+        OverflowPolicy::Block => None,
+        OverflowPolicy::DropOldest => match subscriber.tx.force_send(signal) {
+            Ok(displaced) => {
+                if displaced.is_some() {
+                    subscriber.dropped.fetch_add(1, Ordering::Relaxed);
                 }
-                
-                counter.push_back(now);
-                return true;
+                Some(Delivery::Delivered)
             }
+            Err(_) => Some(Delivery::Closed),
+        },
+        OverflowPolicy::DropNewest => {
+            subscriber.dropped.fetch_add(1, Ordering::Relaxed);
+            Some(Delivery::Dropped)
+        }
+        OverflowPolicy::Disconnect => {
+            println!("Subscriber {} overflowed, disconnecting", id);
+            // Receivers still drain what is queued before seeing the close.
+            subscriber.tx.close();
+            Some(Delivery::Closed)
         }
-        
-        true
     }
+}
 
-    pub async fn cleanup_rate_limit_counters(&self) {
-        let limits = self.rate_limits.lock().await;
-        let mut counters = self.signal_counters.lock().await;
-        let now = Instant::now();
-        
-        for (pattern, rule) in limits.iter() {
-            if let Some(counter) = counters.get_mut(pattern) {
-                counter.retain(|&timestamp| now.duration_since(timestamp) <= rule.time_window);
-            }
+/// Picks the first-run admin password: the env var, then the password file,
+/// then a random one that is written to a 0600 file under the runtime dir.
+async fn resolve_admin_password(config: &DaemonConfig) -> Result<String> {
+    let provided = match std::env::var(ADMIN_PASSWORD_ENV) {
+        Ok(password) => Some(password),
+        Err(_) => match &config.admin_password_file {
+            Some(path) => Some(fs::read_to_string(path).await?.trim().to_string()),
+            None => None,
+        },
+    };
+
+    let password = match provided {
+        Some(password) if password.is_empty() => {
+            return Err(anyhow::anyhow!("The provided admin password is empty"));
         }
+        Some(password) => password,
+        None if config.insecure_default_admin => auth::DEFAULT_ADMIN_PASSWORD.to_string(),
+        None => {
+            let password = auth::random_string(24);
+            write_admin_password(config, &password).await?;
+            password
+        }
+    };
+
+    if password == auth::DEFAULT_ADMIN_PASSWORD && !config.insecure_default_admin {
+        return Err(anyhow::anyhow!(
+            "Refusing to use the well-known default admin password without --insecure-default-admin"
+        ));
     }
+    if password == auth::DEFAULT_ADMIN_PASSWORD {
+        eprintln!("[DAEMON] WARNING: admin is using the insecure default password");
+    }
+
+    Ok(password)
+}
+
+async fn write_admin_password(config: &DaemonConfig, password: &str) -> Result<()> {
+    let dir = dirs::runtime_dir()
+        .map(|dir| dir.join("signalbus"))
+        .or_else(|| config.data_dir.clone());
+
+    let Some(dir) = dir else {
+        println!("[DAEMON] Generated admin password: {}", password);
+        return Ok(());
+    };
+
+    fs::create_dir_all(&dir).await?;
+    fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700)).await?;
+
+    let path = dir.join(ADMIN_PASSWORD_FILE);
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)
+        .await?;
+    file.write_all(format!("{}\n", password).as_bytes()).await?;
+    file.flush().await?;
+
+    println!("[DAEMON] Generated admin password written to {}", path.display());
+    Ok(())
 }
 
-pub async fn run_daemon() -> Result<()> {
+pub async fn run_daemon(config: DaemonConfig) -> Result<()> {
     let _ = fs::remove_file(SOCKET_PATH).await;
     
     let listener = UnixListener::bind(SOCKET_PATH)?;
     println!("Daemon listening on {}", SOCKET_PATH);
     
-    let state = DaemonState::new().await;
+    let state = DaemonState::new(&config).await?;
     
     let cleanup_state = state.clone();
     tokio::spawn(async move {
         start_cleanup_task(cleanup_state).await;
     });
 
+    let sync_state = state.clone();
+    tokio::spawn(async move {
+        start_wal_sync_task(sync_state).await;
+    });
+
+    let schedule_state = state.clone();
+    tokio::spawn(async move {
+        start_schedule_task(schedule_state).await;
+    });
+
+    let cron_state = state.clone();
+    tokio::spawn(async move {
+        start_cron_task(cron_state).await;
+    });
+
     println!("Daemon is ready to accept connections...");
 
     loop {
@@ -369,6 +1719,21 @@ async fn handle_client(mut stream: UnixStream, state: Arc<DaemonState>) -> Resul
     reader.read_line(&mut line).await?;
     let line = line.trim();
 
+    if let Some(version) = line.strip_prefix(HELLO_PREFIX) {
+        let leftover = reader.buffer().to_vec();
+        return match version.parse::<u32>() {
+            Ok(version) if version >= PROTOCOL_VERSION => {
+                stream.write_all(format!("{}{}\n", HELLO_PREFIX, PROTOCOL_VERSION).as_bytes()).await?;
+                stream.flush().await?;
+                session::serve(stream, leftover, state).await
+            }
+            _ => {
+                stream.write_all(b"ERROR:Unsupported protocol version\n").await?;
+                Ok(())
+            }
+        };
+    }
+
     println!("Daemon: Received command: {}", line);
 
     if line.starts_with("LOGIN|") {
@@ -408,24 +1773,25 @@ async fn handle_client(mut stream: UnixStream, state: Arc<DaemonState>) -> Resul
             let expires_in = parts.get(3).and_then(|s| s.parse().ok());
             
             if state.authenticate(token, Some(Permission::Admin)).await {
-                let permissions: Vec<String> = permissions_str.split(',').map(|s| s.to_string()).collect();
-                
-                let mut perms = HashSet::new();
-                for perm_str in permissions {
-                    match perm_str.as_str() {
-                        "Read" => perms.insert(Permission::Read),
-                        "Write" => perms.insert(Permission::Write),
-                        "History" => perms.insert(Permission::History),
-                        "RateLimit" => perms.insert(Permission::RateLimit),
-                        "Admin" => perms.insert(Permission::Admin),
-                        _ => continue,
-                    };
+                let requested = if permissions_str.is_empty() {
+                    Ok(None)
+                } else {
+                    parse_permissions(permissions_str).map(Some)
+                };
+
+                let result = match requested {
+                    Ok(requested) => state.create_token(user_id, requested, HashMap::new(), expires_in).await,
+                    Err(e) => Err(ApiError::invalid(e.to_string())),
+                };
+
+                match result {
+                    Ok(new_token) => {
+                        let _ = stream.write_all(format!("New token created: {}\n", new_token).as_bytes()).await;
+                    }
+                    Err(e) => {
+                        let _ = stream.write_all(format!("ERROR:{}\n", e).as_bytes()).await;
+                    }
                 }
-                
-                state.add_user(user_id.to_string(), "default_password".to_string(), perms).await;
-                
-                let new_token = state.generate_token(user_id.to_string(), expires_in).await;
-                let _ = stream.write_all(format!("New token created: {}\n", new_token).as_bytes()).await;
             } else {
                 let _ = stream.write_all(b"ERROR:Authentication failed or insufficient permissions\n").await;
             }
@@ -443,17 +1809,23 @@ async fn handle_client(mut stream: UnixStream, state: Arc<DaemonState>) -> Resul
             
             if state.authenticate(token, Some(Permission::Write)).await {
                 match serde_json::from_str::<Signal>(signal_json) {
-                    Ok(signal) => {
-                        match state.publish(signal, ttl).await {
-                            Ok(_) => {
-                                let _ = stream.write_all(b"OK\n").await;
-                            }
-                            Err(e) => {
-                                let error_msg = format!("ERROR:{}\n", e);
-                                let _ = stream.write_all(error_msg.as_bytes()).await;
+                    Ok(mut signal) => match state.authorize_signal(Some(token), Permission::Write, &signal.name).await {
+                        Ok(emitter) => {
+                            signal.sender = Some(Sender::new(&emitter, stream.peer_cred().ok()));
+                            match state.publish(signal, ttl, None).await {
+                                Ok(_) => {
+                                    let _ = stream.write_all(b"OK\n").await;
+                                }
+                                Err(e) => {
+                                    let error_msg = format!("ERROR:{}\n", e);
+                                    let _ = stream.write_all(error_msg.as_bytes()).await;
+                                }
                             }
                         }
-                    }
+                        Err(_) => {
+                            let _ = stream.write_all(b"ERROR:Authentication failed or insufficient permissions\n").await;
+                        }
+                    },
                     Err(e) => {
                         eprintln!("Invalid signal JSON: {}", e);
                         let _ = stream.write_all(format!("ERROR:{}\n", e).as_bytes()).await;
@@ -471,21 +1843,51 @@ async fn handle_client(mut stream: UnixStream, state: Arc<DaemonState>) -> Resul
         let parts: Vec<&str> = rest.splitn(2, '|').collect(); 
         if parts.len() == 2 {
             let token = parts[0];
-            let pattern = parts[1].to_string();
+            let pattern = match Pattern::parse(parts[1]) {
+                Ok(pattern) => pattern,
+                Err(e) => {
+                    let _ = stream.write_all(format!("ERROR:{}\n", e).as_bytes()).await;
+                    return Ok(());
+                }
+            };
             
-            if state.authenticate(token, Some(Permission::Read)).await {
-                let (tx, rx) = async_channel::bounded(100);
-                state.subscribe(pattern.clone(), tx).await;
+            if let Ok(caller) = state.authorize_pattern(Some(token), Permission::Read, &pattern).await {
+                let access = state.access(&caller).await;
+                let subscription = match state.subscribe(pattern, &ListenOptions::default(), access).await {
+                    Ok(subscription) => subscription,
+                    Err(e) => {
+                        let _ = stream.write_all(format!("ERROR:{}\n", e).as_bytes()).await;
+                        return Ok(());
+                    }
+                };
+                let rx = subscription.rx;
                 
                 let _ = stream.write_all(b"LISTENING\n").await;
                 let _ = stream.flush().await;
                 
-                while let Ok(signal) = rx.recv().await {
-                    let json = serde_json::to_string(&signal)?;
-                    stream.write_all(json.as_bytes()).await?;
-                    stream.write_all(b"\n").await?;
-                    stream.flush().await?;
-                }
+                let result: Result<()> = async {
+                    // The client sends nothing after LISTEN, so a read only returns on disconnect.
+                    let mut probe = [0u8; 1];
+                    loop {
+                        tokio::select! {
+                            received = rx.recv() => {
+                                let Ok(signal) = received else { break };
+                                let json = serde_json::to_string(&signal.signal)?;
+                                stream.write_all(json.as_bytes()).await?;
+                                stream.write_all(b"\n").await?;
+                                stream.flush().await?;
+                            }
+                            read = stream.read(&mut probe) => {
+                                if matches!(read, Ok(0) | Err(_)) {
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                    Ok(())
+                }.await;
+                state.unsubscribe(subscription.id).await;
+                result?;
             } else {
                 let _ = stream.write_all(b"ERROR:Authentication failed or insufficient permissions\n").await;
             }
@@ -498,12 +1900,18 @@ async fn handle_client(mut stream: UnixStream, state: Arc<DaemonState>) -> Resul
         let parts: Vec<&str> = rest.splitn(3, '|').collect(); 
         if parts.len() == 3 {
             let token = parts[0];
-            let pattern = parts[1];
+            let pattern = match Pattern::parse(parts[1]) {
+                Ok(pattern) => pattern,
+                Err(e) => {
+                    let _ = stream.write_all(format!("ERROR:{}\n", e).as_bytes()).await;
+                    return Ok(());
+                }
+            };
             let limit_str = parts[2];
             
-            if state.authenticate(token, Some(Permission::History)).await {
+            if let Ok(caller) = state.authorize_pattern(Some(token), Permission::History, &pattern).await {
                 let limit = limit_str.parse().unwrap_or(10);
-                let signals = state.get_recent_signals(pattern, limit).await;
+                let signals = state.get_recent_signals(&pattern, limit, &state.access(&caller).await).await;
                 
                 match serde_json::to_string(&signals) {
                     Ok(json) => {
@@ -531,12 +1939,18 @@ async fn handle_client(mut stream: UnixStream, state: Arc<DaemonState>) -> Resul
         let parts: Vec<&str> = rest.splitn(4, '|').collect(); 
         if parts.len() == 4 {
             let token = parts[0];
-            let pattern = parts[1];
+            let pattern = match Pattern::parse(parts[1]) {
+                Ok(pattern) => pattern,
+                Err(e) => {
+                    stream.write_all(format!("ERROR:{}\n", e).as_bytes()).await?;
+                    return Ok(());
+                }
+            };
             let max_signals: u32 = parts[2].parse()?;
             let per_seconds: u64 = parts[3].parse()?;
             
             if state.authenticate(token, Some(Permission::RateLimit)).await {
-                state.set_rate_limit(pattern.to_string(), max_signals, per_seconds).await;
+                state.set_rate_limit(pattern, max_signals, per_seconds).await;
                 stream.write_all(b"Rate limit configured successfully\n").await?;
             } else {
                 stream.write_all(b"ERROR:Authentication failed or insufficient permissions\n").await?;
@@ -569,6 +1983,177 @@ async fn handle_client(mut stream: UnixStream, state: Arc<DaemonState>) -> Resul
         }
         let _ = stream.flush().await;
     } 
+    else if line.starts_with("STATE_EXPORT|") {
+        let token = line.trim_start_matches("STATE_EXPORT|");
+
+        if state.authenticate(token, Some(Permission::Admin)).await {
+            let snapshot = state.export_state().await;
+            match serde_json::to_string(&snapshot) {
+                Ok(json) => {
+                    let _ = stream.write_all(format!("{}\n", json).as_bytes()).await;
+                }
+                Err(e) => {
+                    let _ = stream.write_all(format!("ERROR:{}\n", e).as_bytes()).await;
+                }
+            }
+        } else {
+            let _ = stream.write_all(b"ERROR:Authentication failed or insufficient permissions\n").await;
+        }
+        let _ = stream.flush().await;
+    }
+    else if line.starts_with("STATE_IMPORT|") {
+        let rest = line.trim_start_matches("STATE_IMPORT|");
+        let parts: Vec<&str> = rest.splitn(2, '|').collect();
+
+        if parts.len() == 2 {
+            let token = parts[0];
+            let snapshot_json = parts[1];
+
+            if state.authenticate(token, Some(Permission::Admin)).await {
+                match serde_json::from_str::<StateSnapshot>(snapshot_json) {
+                    Ok(snapshot) => match state.import_state(snapshot).await {
+                        Ok(()) => {
+                            let _ = stream.write_all(b"OK\n").await;
+                        }
+                        Err(e) => {
+                            let _ = stream.write_all(format!("ERROR:{}\n", e).as_bytes()).await;
+                        }
+                    },
+                    Err(e) => {
+                        let _ = stream.write_all(format!("ERROR:Invalid state: {}\n", e).as_bytes()).await;
+                    }
+                }
+            } else {
+                let _ = stream.write_all(b"ERROR:Authentication failed or insufficient permissions\n").await;
+            }
+        } else {
+            let _ = stream.write_all(b"ERROR:Invalid STATE_IMPORT format\n").await;
+        }
+    }
+    else if line.starts_with("USER_ADD|") {
+        let rest = line.trim_start_matches("USER_ADD|");
+        let parts: Vec<&str> = rest.splitn(4, '|').collect();
+
+        if parts.len() == 4 {
+            let token = parts[0];
+            let user_id = parts[1];
+            let permissions_str = parts[2];
+            let password = parts[3];
+
+            if state.authenticate(token, Some(Permission::Admin)).await {
+                let result = match parse_permissions(permissions_str) {
+                    Ok(perms) => state.create_user(user_id, password, perms).await,
+                    Err(e) => Err(ApiError::invalid(e.to_string())),
+                };
+                match result {
+                    Ok(_) => {
+                        let _ = stream.write_all(b"OK\n").await;
+                    }
+                    Err(e) => {
+                        let _ = stream.write_all(format!("ERROR:{}\n", e).as_bytes()).await;
+                    }
+                }
+            } else {
+                let _ = stream.write_all(b"ERROR:Authentication failed or insufficient permissions\n").await;
+            }
+        } else {
+            let _ = stream.write_all(b"ERROR:Invalid USER_ADD format\n").await;
+        }
+    }
+    else if line.starts_with("USER_PASSWD|") {
+        let rest = line.trim_start_matches("USER_PASSWD|");
+        let parts: Vec<&str> = rest.splitn(3, '|').collect();
+
+        if parts.len() == 3 {
+            let token = parts[0];
+            let user_id = parts[1];
+            let password = parts[2];
+
+            // Users may change their own password; changing others' needs Admin.
+            let is_self = state.token_info(token).await.is_some_and(|t| t.user_id == user_id);
+            if is_self || state.authenticate(token, Some(Permission::Admin)).await {
+                match state.set_password(user_id, password).await {
+                    Ok(_) => {
+                        let _ = stream.write_all(b"OK\n").await;
+                    }
+                    Err(e) => {
+                        let _ = stream.write_all(format!("ERROR:{}\n", e).as_bytes()).await;
+                    }
+                }
+            } else {
+                let _ = stream.write_all(b"ERROR:Authentication failed or insufficient permissions\n").await;
+            }
+        } else {
+            let _ = stream.write_all(b"ERROR:Invalid USER_PASSWD format\n").await;
+        }
+    }
+    else if line.starts_with("USER_LIST|") {
+        let token = line.trim_start_matches("USER_LIST|");
+
+        if state.authenticate(token, Some(Permission::Admin)).await {
+            let users = state.list_users().await;
+            match serde_json::to_string(&users) {
+                Ok(json) => {
+                    let _ = stream.write_all(format!("{}\n", json).as_bytes()).await;
+                }
+                Err(e) => {
+                    let _ = stream.write_all(format!("ERROR:{}\n", e).as_bytes()).await;
+                }
+            }
+        } else {
+            let _ = stream.write_all(b"ERROR:Authentication failed or insufficient permissions\n").await;
+        }
+    }
+    else if line.starts_with("USER_DELETE|") {
+        let rest = line.trim_start_matches("USER_DELETE|");
+        let parts: Vec<&str> = rest.splitn(2, '|').collect();
+
+        if parts.len() == 2 {
+            let token = parts[0];
+            let user_id = parts[1];
+
+            if state.authenticate(token, Some(Permission::Admin)).await {
+                if state.delete_user(user_id).await {
+                    let _ = stream.write_all(b"OK\n").await;
+                } else {
+                    let _ = stream.write_all(b"ERROR:User not found\n").await;
+                }
+            } else {
+                let _ = stream.write_all(b"ERROR:Authentication failed or insufficient permissions\n").await;
+            }
+        } else {
+            let _ = stream.write_all(b"ERROR:Invalid USER_DELETE format\n").await;
+        }
+    }
+    else if line.starts_with("USER_SET_PERMISSIONS|") {
+        let rest = line.trim_start_matches("USER_SET_PERMISSIONS|");
+        let parts: Vec<&str> = rest.splitn(3, '|').collect();
+
+        if parts.len() == 3 {
+            let token = parts[0];
+            let user_id = parts[1];
+            let permissions_str = parts[2];
+
+            if state.authenticate(token, Some(Permission::Admin)).await {
+                let result = match parse_permissions(permissions_str) {
+                    Ok(perms) => state.set_user_permissions(user_id, perms).await,
+                    Err(e) => Err(ApiError::invalid(e.to_string())),
+                };
+                match result {
+                    Ok(_) => {
+                        let _ = stream.write_all(b"OK\n").await;
+                    }
+                    Err(e) => {
+                        let _ = stream.write_all(format!("ERROR:{}\n", e).as_bytes()).await;
+                    }
+                }
+            } else {
+                let _ = stream.write_all(b"ERROR:Authentication failed or insufficient permissions\n").await;
+            }
+        } else {
+            let _ = stream.write_all(b"ERROR:Invalid USER_SET_PERMISSIONS format\n").await;
+        }
+    }
     else if line.starts_with("REVOKE_TOKEN|") {
         let rest = line.trim_start_matches("REVOKE_TOKEN|");
         let parts: Vec<&str> = rest.splitn(2, '|').collect();
@@ -599,6 +2184,238 @@ async fn start_cleanup_task(state: Arc<DaemonState>) {
     loop {
         interval.tick().await;
         state.cleanup_expired().await;
+        state.cleanup_subscribers().await;
         state.cleanup_rate_limit_counters().await;
+        state.cleanup_expired_tokens().await;
+    }
+}
+
+async fn start_schedule_task(state: Arc<DaemonState>) {
+    // Re-checks at least this often so a changed system clock is noticed.
+    const MAX_WAIT: Duration = Duration::from_secs(60);
+    loop {
+        let changed = state.schedule_changed.notified();
+        tokio::pin!(changed);
+        changed.as_mut().enable();
+
+        let wait = match state.publish_due_signals().await {
+            Some(due_at) => time_until(due_at).min(MAX_WAIT),
+            None => MAX_WAIT,
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = &mut changed => {}
+        }
+    }
+}
+
+async fn start_cron_task(state: Arc<DaemonState>) {
+    const MAX_WAIT: Duration = Duration::from_secs(60);
+    loop {
+        let changed = state.cron_changed.notified();
+        tokio::pin!(changed);
+        changed.as_mut().enable();
+
+        let wait = match state.run_due_cron_jobs().await {
+            Some(next_run) => time_until(next_run).min(MAX_WAIT),
+            None => MAX_WAIT,
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = &mut changed => {}
+        }
+    }
+}
+
+async fn start_wal_sync_task(state: Arc<DaemonState>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        state.sync_wal().await;
+        state.flush_durables().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscriber(policy: OverflowPolicy, capacity: usize) -> (Subscriber, queue::Receiver) {
+        let (tx, rx) = queue::bounded(capacity);
+        let subscriber = Subscriber {
+            tx,
+            group: None,
+            access: Access::new("tester".to_string(), false, HashSet::new()),
+            policy,
+            block_timeout: DEFAULT_BLOCK_TIMEOUT,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        (subscriber, rx)
+    }
+
+    fn signal(id: u64) -> PersistentSignal {
+        PersistentSignal { signal: Signal::new(format!("test:{}", id), None).unwrap(), id, ttl: None }
+    }
+
+    async fn drain(rx: &queue::Receiver, count: usize) -> Vec<u64> {
+        let mut ids = Vec::new();
+        for _ in 0..count {
+            ids.push(rx.recv().await.unwrap().id);
+        }
+        ids
+    }
+
+    #[tokio::test]
+    async fn block_policy_leaves_full_queue_to_the_caller() {
+        let (subscriber, rx) = subscriber(OverflowPolicy::Block, 1);
+        assert!(matches!(deliver(1, &subscriber, signal(1)), Some(Delivery::Delivered)));
+        assert!(deliver(1, &subscriber, signal(2)).is_none());
+        assert_eq!(subscriber.dropped.load(Ordering::Relaxed), 0);
+        assert_eq!(drain(&rx, 1).await, vec![1]);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_policy_keeps_the_newest_signals() {
+        let (subscriber, rx) = subscriber(OverflowPolicy::DropOldest, 2);
+        for id in 1..=3 {
+            assert!(matches!(deliver(1, &subscriber, signal(id)), Some(Delivery::Delivered)));
+        }
+        assert_eq!(subscriber.dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(drain(&rx, 2).await, vec![2, 3]);
+    }
+
+    #[tokio::test]
+    async fn drop_newest_policy_keeps_the_queued_signals() {
+        let (subscriber, rx) = subscriber(OverflowPolicy::DropNewest, 2);
+        deliver(1, &subscriber, signal(1));
+        deliver(1, &subscriber, signal(2));
+        assert!(matches!(deliver(1, &subscriber, signal(3)), Some(Delivery::Dropped)));
+        assert_eq!(subscriber.dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(drain(&rx, 2).await, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn disconnect_policy_closes_after_queued_signals() {
+        let (subscriber, rx) = subscriber(OverflowPolicy::Disconnect, 1);
+        deliver(1, &subscriber, signal(1));
+        assert!(matches!(deliver(1, &subscriber, signal(2)), Some(Delivery::Closed)));
+        assert_eq!(drain(&rx, 1).await, vec![1]);
+        assert!(rx.recv().await.is_err());
+    }
+
+    #[test]
+    fn time_until_handles_past_and_unrepresentable_times() {
+        assert_eq!(time_until(0), Duration::ZERO);
+        let soon = time_until(unix_now() + 100);
+        assert!(soon > Duration::from_secs(90) && soon <= Duration::from_secs(100));
+        assert_eq!(time_until(u64::MAX), Duration::MAX);
+    }
+
+    #[test]
+    fn user_ids_cannot_contain_separators() {
+        for id in ["alice", "bob-2", "ci_runner"] {
+            assert!(check_user_id(id).is_ok(), "{}", id);
+        }
+        for id in ["", "alice.admin", "ci:build", "two words", "a|b", "tab\t"] {
+            assert!(check_user_id(id).is_err(), "{:?}", id);
+        }
+    }
+
+    async fn memory_state() -> Arc<DaemonState> {
+        let config = DaemonConfig {
+            data_dir: None,
+            fsync: FsyncPolicy::Never,
+            admin_password_file: None,
+            insecure_default_admin: true,
+            peer_auth: None,
+        };
+        DaemonState::new(&config).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn deleting_a_user_removes_grants_to_their_namespace() {
+        let state = memory_state().await;
+        state.create_user("alice", "secret", HashSet::new()).await.unwrap();
+        state.create_user("bob", "secret", HashSet::new()).await.unwrap();
+        state.set_namespace_grant("bob", "alice", true).await.unwrap();
+        assert!(state.set_namespace_grant("bob", "alice.x", true).await.is_err());
+
+        assert!(state.delete_user("alice").await);
+        state.create_user("alice", "secret", HashSet::new()).await.unwrap();
+        let bob = state.list_users().await.into_iter().find(|user| user.user_id == "bob").unwrap();
+        assert!(bob.namespaces.is_empty());
+    }
+
+    #[tokio::test]
+    async fn import_rejects_invalid_user_ids() {
+        let state = memory_state().await;
+        let mut snapshot = state.export_state().await;
+        let mut user = snapshot.users[0].clone();
+        user.user_id = "ci:runner".to_string();
+        snapshot.users.push(user);
+
+        let error = state.import_state(snapshot).await.unwrap_err();
+        assert!(error.message.contains("\"ci:runner\""), "{}", error.message);
+        assert_eq!(state.list_users().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_scheduled_signal_is_retried() {
+        let state = memory_state().await;
+        state.set_rate_limit(Pattern::parse("test.*").unwrap(), 0, 60).await;
+        let token = state.generate_token("admin".to_string(), None).await;
+        let admin = state.token_info(&token).await.unwrap();
+        let signal = Signal::new("test.later".to_string(), None).unwrap();
+        let now = unix_now();
+        let scheduled = state.schedule_signal(signal, None, now, &admin).await.unwrap();
+
+        let next_due = state.publish_due_signals().await.unwrap();
+        assert!(next_due >= now + SCHEDULE_RETRY);
+        let waiting = state.list_scheduled(&admin).await;
+        assert_eq!(waiting.len(), 1);
+        assert_eq!(waiting[0].id, scheduled.id);
+        assert_eq!(waiting[0].due_at, next_due);
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_cannot_be_refreshed() {
+        let state = memory_state().await;
+        let token = state.generate_token("admin".to_string(), Some(u64::MAX)).await;
+        assert_eq!(state.token_info(&token).await.unwrap().expires_at, Some(u64::MAX));
+
+        assert!(state.refresh_token(&token, Some(LOGIN_TOKEN_TTL + 1)).await.is_err());
+        state.refresh_token(&token, None).await.unwrap();
+        state.auth_tokens.lock().await.get_mut(&token).unwrap().expires_at = Some(1);
+        let error = state.refresh_token(&token, None).await.unwrap_err();
+        assert!(matches!(error.code, ErrorCode::Unauthorized));
+    }
+
+    #[tokio::test]
+    async fn replay_keeps_dead_letters_that_fail_to_publish() {
+        let state = memory_state().await;
+        state.set_rate_limit(Pattern::parse("test.*").unwrap(), 0, 60).await;
+        let token = state.generate_token("admin".to_string(), None).await;
+        let admin = state.token_info(&token).await.unwrap();
+        for priority in [Priority::Critical, Priority::Normal] {
+            let mut signal = Signal::new("test.failed".to_string(), None).unwrap();
+            signal.priority = priority;
+            signal.sender = Some(Sender::new(&admin, None));
+            state.dead_letter(DeadLetter {
+                id: 0,
+                signal: PersistentSignal { signal, id: 1, ttl: None },
+                pattern: "test.*".to_string(),
+                user_id: "admin".to_string(),
+                durable: None,
+                failures: 1,
+                reason: "nacked".to_string(),
+                dead_lettered_at: 0,
+            }).await;
+        }
+
+        // The critical one skips the rate limit like its emitter could.
+        let error = state.replay_dead_letters(None, &admin).await.unwrap_err();
+        assert!(matches!(error.code, ErrorCode::RateLimited));
+        let left: Vec<u64> = state.list_dead_letters().await.iter().map(|dead_letter| dead_letter.id).collect();
+        assert_eq!(left, vec![2]);
     }
 }
@@ -0,0 +1,70 @@
+use crate::models::DeadLetter;
+use crate::json_file::JsonFile;
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::path::Path;
+
+const DEAD_LETTER_FILE: &str = "dead-letters.json";
+/// Dead letters kept before the oldest are discarded.
+pub const DEAD_LETTER_LIMIT: usize = 10_000;
+
+/// The dead-letter queue: signals consumers gave up on, oldest first.
+pub struct DeadLetters {
+    entries: VecDeque<DeadLetter>,
+    next_id: u64,
+    file: JsonFile,
+}
+
+impl DeadLetters {
+    pub fn in_memory() -> Self {
+        DeadLetters { entries: VecDeque::new(), next_id: 1, file: JsonFile::in_memory() }
+    }
+
+    pub async fn open(dir: &Path) -> Result<Self> {
+        let (file, entries): (_, VecDeque<DeadLetter>) = JsonFile::open(dir, DEAD_LETTER_FILE).await?;
+        let next_id = entries.iter().map(|entry| entry.id + 1).max().unwrap_or(1);
+        Ok(DeadLetters { entries, next_id, file })
+    }
+
+    /// Adds a dead letter, assigning its id.
+    pub fn push(&mut self, mut dead_letter: DeadLetter) -> u64 {
+        dead_letter.id = self.next_id;
+        self.next_id += 1;
+        self.entries.push_back(dead_letter);
+        if self.entries.len() > DEAD_LETTER_LIMIT {
+            self.entries.pop_front();
+        }
+        self.next_id - 1
+    }
+
+    pub fn list(&self) -> Vec<DeadLetter> {
+        self.entries.iter().cloned().collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<&DeadLetter> {
+        self.entries.iter().find(|entry| entry.id == id)
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<DeadLetter> {
+        let position = self.entries.iter().position(|entry| entry.id == id)?;
+        self.entries.remove(position)
+    }
+
+    /// Puts back dead letters taken out with `remove`, keeping their ids.
+    pub fn restore(&mut self, dead_letters: Vec<DeadLetter>) {
+        for dead_letter in dead_letters {
+            let position = self.entries.partition_point(|entry| entry.id < dead_letter.id);
+            self.entries.insert(position, dead_letter);
+        }
+    }
+
+    pub fn clear(&mut self) -> usize {
+        let count = self.entries.len();
+        self.entries.clear();
+        count
+    }
+
+    pub async fn save(&self) -> Result<()> {
+        self.file.save(&self.entries).await
+    }
+}
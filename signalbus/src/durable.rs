@@ -0,0 +1,316 @@
+use crate::models::{DurableInfo, DurableSubscription, PersistentSignal};
+use crate::pattern::Pattern;
+use crate::protocol::{ApiError, ErrorCode};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+/// Unacknowledged signals kept per durable subscription; the oldest are
+/// dropped beyond this.
+pub const DURABLE_BUFFER_LIMIT: usize = 10_000;
+
+struct Durable {
+    record: DurableSubscription,
+    pattern: Pattern,
+    /// Matching signals after the cursor, in id order.
+    pending: VecDeque<PersistentSignal>,
+    dropped: u64,
+    /// Highest acknowledged id; the cursor trails it while older signals
+    /// are still unacknowledged.
+    acked: u64,
+    /// Subscription id of the connected consumer, if any.
+    attached: Option<u64>,
+    notify: Arc<Notify>,
+}
+
+impl Durable {
+    fn new(record: DurableSubscription, pattern: Pattern) -> Self {
+        Durable {
+            acked: record.cursor,
+            record,
+            pattern,
+            pending: VecDeque::new(),
+            dropped: 0,
+            attached: None,
+            notify: Arc::new(Notify::new()),
+        }
+    }
+
+    fn push(&mut self, signal: PersistentSignal) {
+        // Ids are assigned before history order is settled, so keep the buffer sorted.
+        let position = self.pending.partition_point(|pending| pending.id < signal.id);
+        if self.pending.get(position).is_some_and(|pending| pending.id == signal.id) {
+            return;
+        }
+        self.pending.insert(position, signal);
+
+        if self.pending.len() > DURABLE_BUFFER_LIMIT {
+            self.pending.pop_front();
+            self.dropped += 1;
+        }
+    }
+}
+
+/// The consumer is no longer attached, e.g. because the subscription was deleted.
+#[derive(Debug)]
+pub struct Detached;
+
+/// What a consumer needs to read a durable subscription it attached to.
+pub struct Attachment {
+    pub cursor: u64,
+    pub notify: Arc<Notify>,
+}
+
+/// Durable subscriptions by name. Cursors are persisted in the state file;
+/// pending signals are rebuilt from the WAL on startup.
+#[derive(Default)]
+pub struct Durables {
+    entries: HashMap<String, Durable>,
+    dirty: bool,
+}
+
+impl Durables {
+    pub fn restore(records: Vec<DurableSubscription>) -> Self {
+        let entries = records.into_iter()
+            .filter_map(|record| match Pattern::parse(&record.pattern) {
+                Ok(pattern) => Some((record.name.clone(), Durable::new(record, pattern))),
+                Err(e) => {
+                    eprintln!("Dropping durable subscription '{}': {}", record.name, e);
+                    None
+                }
+            })
+            .collect();
+        Durables { entries, dirty: false }
+    }
+
+    /// Refills the buffers from stored signals, e.g. the WAL at startup.
+    pub fn rebuild<'a>(&mut self, signals: impl IntoIterator<Item = &'a PersistentSignal>) {
+        for durable in self.entries.values_mut() {
+            durable.pending.clear();
+        }
+        for signal in signals {
+            self.offer(signal);
+        }
+    }
+
+    /// Buffers a newly published signal for every durable subscription it matches.
+    pub fn offer(&mut self, signal: &PersistentSignal) {
+        for durable in self.entries.values_mut() {
+            if signal.id > durable.record.cursor && durable.pattern.matches(&signal.signal.name) {
+                durable.push(signal.clone());
+                durable.notify.notify_one();
+            }
+        }
+    }
+
+    /// Connects a consumer, creating the subscription on first use.
+    pub fn attach(
+        &mut self,
+        name: &str,
+        pattern: &Pattern,
+        user_id: &str,
+        admin: bool,
+        subscription: u64,
+        start: u64,
+    ) -> Result<Attachment, ApiError> {
+        let durable = self.entries.entry(name.to_string()).or_insert_with(|| {
+            let record = DurableSubscription {
+                name: name.to_string(),
+                pattern: pattern.to_string(),
+                user_id: user_id.to_string(),
+                cursor: start,
+            };
+            Durable::new(record, pattern.clone())
+        });
+
+        if durable.record.user_id != user_id && !admin {
+            return Err(ApiError::unauthorized());
+        }
+        if durable.pattern != *pattern {
+            return Err(ApiError::invalid(format!(
+                "Durable subscription '{}' listens on '{}'", name, durable.pattern
+            )));
+        }
+        if durable.attached.is_some() {
+            return Err(ApiError::new(
+                ErrorCode::AlreadyExists,
+                format!("Durable subscription '{}' already has a consumer", name),
+            ));
+        }
+
+        durable.attached = Some(subscription);
+        self.dirty = true;
+        Ok(Attachment { cursor: durable.record.cursor, notify: durable.notify.clone() })
+    }
+
+    /// Drops buffered signals whose TTL has passed.
+    pub fn expire(&mut self, now: u64) {
+        for durable in self.entries.values_mut() {
+            durable.pending.retain(|ps| ps.is_live(now));
+        }
+    }
+
+    /// Wakes every attached consumer, e.g. before the set is replaced, so
+    /// they notice their subscription is gone.
+    pub fn close(&self) {
+        for durable in self.entries.values() {
+            durable.notify.notify_one();
+        }
+    }
+
+    pub fn detach(&mut self, subscription: u64) -> bool {
+        match self.entries.values_mut().find(|durable| durable.attached == Some(subscription)) {
+            Some(durable) => {
+                durable.attached = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The first buffered signal after `after` for an attached consumer.
+    pub fn next_after(&self, subscription: u64, after: u64) -> Result<Option<PersistentSignal>, Detached> {
+        let durable = self.entries.values()
+            .find(|durable| durable.attached == Some(subscription))
+            .ok_or(Detached)?;
+        let position = durable.pending.partition_point(|pending| pending.id <= after);
+        Ok(durable.pending.get(position).cloned())
+    }
+
+    /// Removes an acknowledged signal from the attached subscription's buffer.
+    /// The cursor moves up to the oldest signal that is still unacknowledged.
+    pub fn ack(&mut self, subscription: u64, id: u64) {
+        let Some(durable) = self.entries.values_mut().find(|durable| durable.attached == Some(subscription)) else {
+            return;
+        };
+        let Ok(position) = durable.pending.binary_search_by_key(&id, |pending| pending.id) else {
+            return;
+        };
+        durable.pending.remove(position);
+        durable.acked = durable.acked.max(id);
+
+        let cursor = match durable.pending.front() {
+            Some(oldest) => durable.acked.min(oldest.id - 1),
+            None => durable.acked,
+        };
+        if cursor > durable.record.cursor {
+            durable.record.cursor = cursor;
+            self.dirty = true;
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<DurableSubscription> {
+        let durable = self.entries.remove(name)?;
+        // Wakes an attached consumer so it notices the subscription is gone.
+        durable.notify.notify_one();
+        self.dirty = true;
+        Some(durable.record)
+    }
+
+    pub fn owner(&self, name: &str) -> Option<&str> {
+        self.entries.get(name).map(|durable| durable.record.user_id.as_str())
+    }
+
+    pub fn records(&self) -> Vec<DurableSubscription> {
+        let mut records: Vec<_> = self.entries.values().map(|durable| durable.record.clone()).collect();
+        records.sort_by(|a, b| a.name.cmp(&b.name));
+        records
+    }
+
+    pub fn list(&self) -> Vec<DurableInfo> {
+        let mut list: Vec<DurableInfo> = self.entries.values()
+            .map(|durable| DurableInfo {
+                name: durable.record.name.clone(),
+                pattern: durable.record.pattern.clone(),
+                user_id: durable.record.user_id.clone(),
+                cursor: durable.record.cursor,
+                pending: durable.pending.len(),
+                dropped: durable.dropped,
+                attached: durable.attached.is_some(),
+            })
+            .collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+
+    /// Ids the WAL must keep because a durable subscription still needs them.
+    pub fn pending_ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.entries.values().flat_map(|durable| durable.pending.iter().map(|pending| pending.id))
+    }
+
+    /// Whether cursors changed since the last call.
+    pub fn take_dirty(&mut self) -> bool {
+        std::mem::take(&mut self.dirty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Signal;
+
+    fn signal(id: u64) -> PersistentSignal {
+        PersistentSignal {
+            signal: Signal::new(format!("test.{}", id), None).unwrap(),
+            id,
+            ttl: None,
+        }
+    }
+
+    fn attach(durables: &mut Durables, subscription: u64) -> Attachment {
+        let pattern = Pattern::parse("test.*").unwrap();
+        durables.attach("jobs", &pattern, "tester", false, subscription, 0).unwrap()
+    }
+
+    fn cursor(durables: &Durables) -> u64 {
+        durables.records()[0].cursor
+    }
+
+    #[test]
+    fn out_of_order_acks_move_the_cursor_past_the_oldest_unacked() {
+        let mut durables = Durables::default();
+        attach(&mut durables, 1);
+        for id in 1..=3 {
+            durables.offer(&signal(id));
+        }
+
+        durables.ack(1, 2);
+        assert_eq!(cursor(&durables), 0);
+        durables.ack(1, 1);
+        assert_eq!(cursor(&durables), 2);
+        durables.ack(1, 3);
+        assert_eq!(cursor(&durables), 3);
+        assert!(durables.take_dirty());
+    }
+
+    #[test]
+    fn reattaching_resumes_after_the_last_acked_signal() {
+        let mut durables = Durables::default();
+        attach(&mut durables, 1);
+        for id in 1..=3 {
+            durables.offer(&signal(id));
+        }
+        durables.ack(1, 1);
+        assert!(durables.detach(1));
+
+        // As after a restart: cursors from the state file, buffers from the WAL.
+        let mut durables = Durables::restore(durables.records());
+        durables.rebuild(&(1..=3).map(signal).collect::<Vec<_>>());
+        let attachment = attach(&mut durables, 2);
+        assert_eq!(attachment.cursor, 1);
+        let next = durables.next_after(2, attachment.cursor).unwrap().unwrap();
+        assert_eq!(next.id, 2);
+    }
+
+    #[test]
+    fn a_durable_has_one_consumer_and_its_owner() {
+        let mut durables = Durables::default();
+        let pattern = Pattern::parse("test.*").unwrap();
+        attach(&mut durables, 1);
+        assert!(durables.attach("jobs", &pattern, "tester", false, 2, 0).is_err());
+        durables.detach(1);
+        assert!(durables.attach("jobs", &pattern, "someone", false, 2, 0).is_err());
+        assert!(durables.attach("jobs", &Pattern::parse("other.*").unwrap(), "tester", false, 2, 0).is_err());
+        assert!(durables.next_after(1, 0).is_err());
+    }
+}
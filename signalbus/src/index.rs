@@ -0,0 +1,313 @@
+use crate::pattern::{self, Pattern, Segment, SEPARATORS};
+use std::collections::{HashMap, HashSet};
+
+/// Trie of subscription patterns keyed on name segments.
+///
+/// Literal segments are looked up by hash, so a publish only walks the
+/// branches that can match the signal name instead of testing every pattern.
+/// Glob segments (`*`, `?`) are tried in turn at their node and `**` edges
+/// fan out over every possible number of skipped segments. Each node is
+/// visited at most once per position in the name, however many `**` lead
+/// there.
+pub struct SubscriptionIndex<T> {
+    root: Node<T>,
+    patterns: HashMap<u64, Pattern>,
+}
+
+struct Node<T> {
+    literals: HashMap<String, Node<T>>,
+    globs: Vec<(Segment, Node<T>)>,
+    any: Option<Box<Node<T>>>,
+    entries: Vec<(u64, T)>,
+}
+
+/// Nodes already collected from, with the number of names left there.
+type Visited<T> = HashSet<(*const Node<T>, usize)>;
+
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        Node {
+            literals: HashMap::new(),
+            globs: Vec::new(),
+            any: None,
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<T> Node<T> {
+    fn child_mut(&mut self, segment: &Segment) -> &mut Node<T> {
+        match segment {
+            Segment::Literal(literal) => self.literals.entry(literal.clone()).or_default(),
+            Segment::Any => self.any.get_or_insert_with(Default::default),
+            Segment::Glob(_) => {
+                let position = match self.globs.iter().position(|(glob, _)| glob == segment) {
+                    Some(position) => position,
+                    None => {
+                        self.globs.push((segment.clone(), Node::default()));
+                        self.globs.len() - 1
+                    }
+                };
+                &mut self.globs[position].1
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.literals.is_empty() && self.globs.is_empty() && self.any.is_none()
+    }
+
+    /// Removes an entry at the end of `segments`, pruning nodes left empty.
+    fn remove(&mut self, segments: &[Segment], id: u64) -> Option<T> {
+        let Some((segment, rest)) = segments.split_first() else {
+            let position = self.entries.iter().position(|(entry, _)| *entry == id)?;
+            return Some(self.entries.remove(position).1);
+        };
+
+        match segment {
+            Segment::Literal(literal) => {
+                let child = self.literals.get_mut(literal)?;
+                let removed = child.remove(rest, id);
+                if child.is_empty() {
+                    self.literals.remove(literal);
+                }
+                removed
+            }
+            Segment::Any => {
+                let child = self.any.as_mut()?;
+                let removed = child.remove(rest, id);
+                if child.is_empty() {
+                    self.any = None;
+                }
+                removed
+            }
+            Segment::Glob(_) => {
+                let position = self.globs.iter().position(|(glob, _)| glob == segment)?;
+                let removed = self.globs[position].1.remove(rest, id);
+                if self.globs[position].1.is_empty() {
+                    self.globs.remove(position);
+                }
+                removed
+            }
+        }
+    }
+
+    fn retain(&mut self, keep: &mut impl FnMut(&T) -> bool, removed: &mut Vec<u64>) {
+        self.entries.retain(|(id, value)| {
+            let kept = keep(value);
+            if !kept {
+                removed.push(*id);
+            }
+            kept
+        });
+        self.literals.retain(|_, child| {
+            child.retain(keep, removed);
+            !child.is_empty()
+        });
+        self.globs.retain_mut(|(_, child)| {
+            child.retain(keep, removed);
+            !child.is_empty()
+        });
+        if let Some(any) = &mut self.any {
+            any.retain(keep, removed);
+            if any.is_empty() {
+                self.any = None;
+            }
+        }
+    }
+
+    fn collect<'a>(&'a self, names: &[&str], visited: &mut Visited<T>, seen: &mut HashSet<u64>, out: &mut Vec<(u64, &'a T)>) {
+        if !visited.insert((std::ptr::from_ref(self), names.len())) {
+            return;
+        }
+        if let Some(any) = &self.any {
+            for skip in 0..=names.len() {
+                any.collect(&names[skip..], visited, seen, out);
+            }
+        }
+
+        let Some((name, rest)) = names.split_first() else {
+            for (id, value) in &self.entries {
+                if seen.insert(*id) {
+                    out.push((*id, value));
+                }
+            }
+            return;
+        };
+
+        if let Some(child) = self.literals.get(*name) {
+            child.collect(rest, visited, seen, out);
+        }
+        for (glob, child) in &self.globs {
+            if pattern::match_segment(glob, name) {
+                child.collect(rest, visited, seen, out);
+            }
+        }
+    }
+}
+
+impl<T> SubscriptionIndex<T> {
+    pub fn new() -> Self {
+        SubscriptionIndex { root: Node::default(), patterns: HashMap::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.patterns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Adds a value under a pattern. Ids are chosen by the caller and must be unique.
+    pub fn insert(&mut self, pattern: &Pattern, id: u64, value: T) {
+        let node = pattern.segments()
+            .iter()
+            .fold(&mut self.root, |node, segment| node.child_mut(segment));
+
+        node.entries.push((id, value));
+        self.patterns.insert(id, pattern.clone());
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<T> {
+        let pattern = self.patterns.remove(&id)?;
+        self.root.remove(pattern.segments(), id)
+    }
+
+    /// Drops every entry the predicate rejects and returns their ids.
+    pub fn retain(&mut self, mut keep: impl FnMut(&T) -> bool) -> Vec<u64> {
+        let mut removed = Vec::new();
+        self.root.retain(&mut keep, &mut removed);
+        for id in &removed {
+            self.patterns.remove(id);
+        }
+        removed
+    }
+
+    /// Entry ids and values of every pattern matching the name, each at most once.
+    pub fn matches(&self, signal_name: &str) -> Vec<(u64, &T)> {
+        let names: Vec<&str> = signal_name.split(SEPARATORS).collect();
+        let mut seen = HashSet::new();
+        let mut out = Vec::new();
+        self.root.collect(&names, &mut HashSet::new(), &mut seen, &mut out);
+        out
+    }
+}
+
+impl<T> Default for SubscriptionIndex<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATTERNS: &[&str] = &[
+        "*", "**", "build", "build:*", "build:**", "build.done", "build:st*", "build:?",
+        "*:done", "**:done", "a:**:z", "**:**", "a:*:**", "user.*.created", "user:**",
+        "system:*.high", "disk?.full", "deploy-*.done", "x:y:z",
+    ];
+
+    const NAMES: &[&str] = &[
+        "build", "build:done", "build.done", "build:step:done", "build:started", "build:1",
+        "deploy:done", "a:z", "a:b:z", "a:b:c:z", "a:b", "user.alice.created", "user:created",
+        "system.cpu.high", "system:cpu:low", "disk1.full", "disk12.full", "deploy-web.done",
+        "x:y:z", "x", "done",
+    ];
+
+    fn index() -> (SubscriptionIndex<&'static str>, Vec<(u64, Pattern)>) {
+        let mut index = SubscriptionIndex::new();
+        let mut patterns = Vec::new();
+        for (id, source) in PATTERNS.iter().enumerate() {
+            let pattern = Pattern::parse(source).unwrap();
+            index.insert(&pattern, id as u64, *source);
+            patterns.push((id as u64, pattern));
+        }
+        (index, patterns)
+    }
+
+    fn trie_ids(index: &SubscriptionIndex<&str>, name: &str) -> Vec<u64> {
+        let mut ids: Vec<u64> = index.matches(name).into_iter().map(|(id, _)| id).collect();
+        ids.sort();
+        ids
+    }
+
+    fn linear_ids(patterns: &[(u64, Pattern)], name: &str) -> Vec<u64> {
+        patterns.iter().filter(|(_, pattern)| pattern.matches(name)).map(|(id, _)| *id).collect()
+    }
+
+    #[test]
+    fn matches_like_a_linear_scan() {
+        let (index, patterns) = index();
+        assert_eq!(index.len(), PATTERNS.len());
+        for name in NAMES {
+            assert_eq!(trie_ids(&index, name), linear_ids(&patterns, name), "matches for {}", name);
+        }
+    }
+
+    #[test]
+    fn reports_each_entry_once() {
+        let mut index = SubscriptionIndex::new();
+        // Both `**` edges can absorb the middle segments in several ways.
+        index.insert(&Pattern::parse("**:**").unwrap(), 1, ());
+        index.insert(&Pattern::parse("a:**:**:z").unwrap(), 2, ());
+        let ids: Vec<u64> = index.matches("a:b:c:z").into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids.len(), 2);
+        assert!(ids.contains(&1) && ids.contains(&2));
+    }
+
+    #[test]
+    fn shares_nodes_between_identical_patterns() {
+        let mut index = SubscriptionIndex::new();
+        let pattern = Pattern::parse("build:*").unwrap();
+        index.insert(&pattern, 1, "first");
+        index.insert(&pattern, 2, "second");
+        let mut values: Vec<&str> = index.matches("build:done").into_iter().map(|(_, value)| *value).collect();
+        values.sort();
+        assert_eq!(values, vec!["first", "second"]);
+
+        assert_eq!(index.remove(1), Some("first"));
+        assert_eq!(trie_ids(&index, "build:done"), vec![2]);
+    }
+
+    #[test]
+    fn removal_and_retain_keep_matching_consistent() {
+        let (mut index, mut patterns) = index();
+        for id in [0, 4, 11] {
+            assert!(index.remove(id).is_some());
+            assert!(index.remove(id).is_none());
+        }
+        patterns.retain(|(id, _)| ![0, 4, 11].contains(id));
+
+        let removed = index.retain(|source| !source.contains('?'));
+        let mut expected: Vec<u64> = patterns.iter().filter(|(_, p)| p.as_str().contains('?')).map(|(id, _)| *id).collect();
+        let mut removed_sorted = removed.clone();
+        removed_sorted.sort();
+        expected.sort();
+        assert_eq!(removed_sorted, expected);
+        patterns.retain(|(id, _)| !removed.contains(id));
+
+        assert_eq!(index.len(), patterns.len());
+        for name in NAMES {
+            assert_eq!(trie_ids(&index, name), linear_ids(&patterns, name), "matches for {}", name);
+        }
+
+        for (id, _) in &patterns {
+            index.remove(*id);
+        }
+        assert!(index.is_empty());
+        assert!(index.root.is_empty());
+    }
+
+    #[test]
+    fn many_globstars_do_not_blow_up() {
+        let mut index = SubscriptionIndex::new();
+        let anchored = Pattern::parse(&format!("{}:z", vec!["**"; 30].join(":"))).unwrap();
+        index.insert(&anchored, 1, "anchored");
+        let name = vec!["a"; 60].join(":");
+        assert!(index.matches(&name).is_empty());
+        assert_eq!(trie_ids(&index, &format!("{}:z", name)), vec![1]);
+    }
+}
@@ -0,0 +1,58 @@
+use crate::store::write_atomic;
+use anyhow::Result;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// A JSON file in the data directory holding one of the daemon's smaller
+/// collections. Without a data directory it is kept in memory only and
+/// saving does nothing.
+pub struct JsonFile {
+    location: Option<(PathBuf, PathBuf)>,
+}
+
+impl JsonFile {
+    pub fn in_memory() -> Self {
+        JsonFile { location: None }
+    }
+
+    /// Opens `name` in `dir` and reads it, or the default value when the
+    /// file does not exist yet.
+    pub async fn open<T: DeserializeOwned + Default>(dir: &Path, name: &str) -> Result<(Self, T)> {
+        let path = dir.join(name);
+        let value = match fs::read(&path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => T::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok((JsonFile { location: Some((dir.to_path_buf(), path)) }, value))
+    }
+
+    /// Replaces the file's contents atomically.
+    pub async fn save<T: Serialize + ?Sized>(&self, value: &T) -> Result<()> {
+        if let Some((dir, path)) = &self.location {
+            write_atomic(dir, path, &serde_json::to_vec_pretty(value)?).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn saved_values_are_read_back() {
+        let dir = std::env::temp_dir().join(format!("signalbus-json-file-{}", std::process::id()));
+        fs::create_dir_all(&dir).await.unwrap();
+
+        let (file, empty): (_, Vec<u64>) = JsonFile::open(&dir, "values.json").await.unwrap();
+        assert!(empty.is_empty());
+        file.save(&[1u64, 2, 3]).await.unwrap();
+
+        let (_, values): (_, Vec<u64>) = JsonFile::open(&dir, "values.json").await.unwrap();
+        assert_eq!(values, vec![1, 2, 3]);
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -1,3 +1,19 @@
+pub mod auth;
 pub mod cli;
+pub mod client;
+pub mod cron;
 pub mod daemon;
+pub mod dlq;
+pub mod durable;
+pub mod index;
+pub mod json_file;
 pub mod models;
+pub mod namespace;
+pub mod pattern;
+pub mod peer;
+pub mod protocol;
+pub mod queue;
+pub mod schedule;
+pub mod session;
+pub mod store;
+pub mod wal;
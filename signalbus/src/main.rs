@@ -1,7 +1,5 @@
-mod cli;
-mod daemon;
-mod models;
-
+use signalbus::protocol::ListenOptions;
+use signalbus::{cli, daemon};
 use anyhow::Result;
 use clap::Parser;
 
@@ -9,20 +7,57 @@ fn main() -> Result<()> {
     let cli = cli::Cli::parse();
     
     match cli.command {
-        cli::Command::Emit { signal, payload, ttl, token } => {
+        cli::Command::Emit { signal, payload, ttl, priority, at, delay, token } => {
+            let at = at.or(delay.map(cli::delay_until));
+            tokio::runtime::Runtime::new()?.block_on(async {
+                cli::emit_signal(signal, payload, ttl, priority, at, token).await
+            })?;
+        }
+        cli::Command::Request { signal, payload, timeout, token } => {
             tokio::runtime::Runtime::new()?.block_on(async {
-                cli::emit_signal(signal, payload, ttl, token).await
+                cli::send_request(signal, payload, timeout, token).await
             })?;
         }
-        cli::Command::Listen { pattern, exec, token } => {
+        cli::Command::Serve { pattern, exec, group, token } => {
             tokio::runtime::Runtime::new()?.block_on(async {
-                cli::listen_signals(pattern, exec, token).await
+                cli::serve_requests(pattern, exec, group, token).await
             })?;
         }
-        cli::Command::Daemon => {
+        cli::Command::Listen { pattern, exec, overflow, block_timeout, from_id, since, last, ack, ack_timeout, max_retries, group, balance, durable, token } => {
+            let options = ListenOptions {
+                overflow,
+                block_timeout_ms: block_timeout,
+                from_id,
+                since,
+                last,
+                ack,
+                ack_timeout_ms: ack_timeout,
+                max_retries,
+                group,
+                balance,
+                durable,
+                ..Default::default()
+            };
+            tokio::runtime::Runtime::new()?.block_on(async {
+                cli::listen_signals(pattern, exec, options, token).await
+            })?;
+        }
+        cli::Command::Daemon { data_dir, in_memory, fsync, admin_password_file, insecure_default_admin, peer_auth } => {
             println!("Starting SignalBus daemon...");
+            let data_dir = if in_memory {
+                None
+            } else {
+                data_dir.or_else(daemon::DaemonConfig::default_data_dir)
+            };
+            let config = daemon::DaemonConfig {
+                data_dir,
+                fsync,
+                admin_password_file,
+                insecure_default_admin,
+                peer_auth,
+            };
             tokio::runtime::Runtime::new()?.block_on(async {
-                daemon::run_daemon().await
+                daemon::run_daemon(config).await
             })?;
         }
         cli::Command::History { pattern, limit, token } => {
@@ -55,9 +90,9 @@ fn main() -> Result<()> {
                 println!("Not logged in");
             }
         }
-        cli::Command::CreateToken { user_id, permissions, expires_in } => {
+        cli::Command::CreateToken { user_id, permissions, scopes, expires_in } => {
             tokio::runtime::Runtime::new()?.block_on(async {
-                cli::create_token(user_id, permissions, expires_in).await
+                cli::create_token(user_id, permissions, scopes, expires_in).await
             })?;
         }
         cli::Command::RevokeToken { token, admin_token } => {
@@ -65,6 +100,44 @@ fn main() -> Result<()> {
                 cli::revoke_token(token, admin_token).await
             })?;
         }
+        cli::Command::State { action } => {
+            tokio::runtime::Runtime::new()?.block_on(async {
+                match action {
+                    cli::StateCommand::Export { output, token } => cli::export_state(output, token).await,
+                    cli::StateCommand::Import { input, token } => cli::import_state(input, token).await,
+                }
+            })?;
+        }
+        cli::Command::User { action } => {
+            tokio::runtime::Runtime::new()?.block_on(async {
+                cli::manage_users(action).await
+            })?;
+        }
+        cli::Command::Schedule { action } => {
+            tokio::runtime::Runtime::new()?.block_on(async {
+                cli::manage_schedule(action).await
+            })?;
+        }
+        cli::Command::Token { action } => {
+            tokio::runtime::Runtime::new()?.block_on(async {
+                cli::manage_tokens(action).await
+            })?;
+        }
+        cli::Command::Cron { action } => {
+            tokio::runtime::Runtime::new()?.block_on(async {
+                cli::manage_cron(action).await
+            })?;
+        }
+        cli::Command::Dlq { action } => {
+            tokio::runtime::Runtime::new()?.block_on(async {
+                cli::manage_dead_letters(action).await
+            })?;
+        }
+        cli::Command::Durable { action } => {
+            tokio::runtime::Runtime::new()?.block_on(async {
+                cli::manage_durables(action).await
+            })?;
+        }
     }
     
     Ok(())
@@ -1,14 +1,76 @@
+use crate::auth;
+use crate::pattern::Pattern;
 use serde::{Deserialize, Serialize};
 use std::time::{SystemTime, UNIX_EPOCH};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use tokio::net::unix::UCred;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AuthToken {
     pub token: String,
+    /// Names the token in signal metadata without revealing it. Tokens
+    /// saved before ids existed get one on load.
+    #[serde(default)]
+    pub id: String,
     pub user_id: String,
     pub permissions: HashSet<Permission>,
     pub created_at: u64,
     pub expires_at: Option<u64>,
+    /// Patterns limiting which signals a permission applies to. Permissions
+    /// without an entry apply to every signal.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub scopes: HashMap<Permission, Vec<String>>,
+    /// Issued to a connection authenticated by its peer credentials. It
+    /// lives as long as the connection and is never persisted.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub peer: bool,
+    /// When the token last authorized a request. Kept in memory and saved
+    /// with the next state change.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used: Option<u64>,
+}
+
+impl AuthToken {
+    /// Whether the token may use `permission` on the signal name.
+    pub fn allows_signal(&self, permission: &Permission, name: &str) -> bool {
+        self.allows(permission, |scope| scope.matches(name))
+    }
+
+    /// Whether the token may use `permission` on every signal the pattern matches.
+    pub fn allows_pattern(&self, permission: &Permission, pattern: &Pattern) -> bool {
+        self.allows(permission, |scope| scope.covers(pattern))
+    }
+
+    fn allows(&self, permission: &Permission, within: impl Fn(&Pattern) -> bool) -> bool {
+        if self.permissions.contains(&Permission::Admin) {
+            return true;
+        }
+        match self.scopes.get(permission) {
+            Some(scopes) => scopes.iter()
+                .filter_map(|scope| Pattern::parse(scope).ok())
+                .any(|scope| within(&scope)),
+            None => true,
+        }
+    }
+}
+
+/// Parses `--scope` values such as `Write=ci:**` into scopes by permission.
+pub fn parse_scopes<S: AsRef<str>>(specs: &[S]) -> anyhow::Result<HashMap<Permission, Vec<String>>> {
+    let mut scopes: HashMap<Permission, Vec<String>> = HashMap::new();
+    for spec in specs {
+        let spec = spec.as_ref();
+        let (permission, pattern) = spec.split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected PERMISSION=PATTERN, got '{}'", spec))?;
+        let permission = Permission::from_str(permission)?;
+        if !matches!(permission, Permission::Read | Permission::Write | Permission::History) {
+            anyhow::bail!("Only Read, Write and History can be scoped, not {:?}", permission);
+        }
+        let pattern = Pattern::parse(pattern)?;
+        scopes.entry(permission).or_default().push(pattern.to_string());
+    }
+    Ok(scopes)
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
@@ -20,6 +82,246 @@ pub enum Permission {
     Admin,     
 }
 
+impl FromStr for Permission {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "Read" => Ok(Permission::Read),
+            "Write" => Ok(Permission::Write),
+            "History" => Ok(Permission::History),
+            "RateLimit" => Ok(Permission::RateLimit),
+            "Admin" => Ok(Permission::Admin),
+            other => Err(anyhow::anyhow!("Unknown permission: {}", other)),
+        }
+    }
+}
+
+/// Parses a comma separated permission list such as `Read,Write`.
+pub fn parse_permissions(list: &str) -> anyhow::Result<HashSet<Permission>> {
+    list.split(',')
+        .filter(|p| !p.trim().is_empty())
+        .map(Permission::from_str)
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct User {
+    pub user_id: String,
+    pub password_hash: String,
+    pub permissions: HashSet<Permission>,
+    /// Other users whose private namespaces this user was granted.
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub namespaces: HashSet<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserInfo {
+    pub user_id: String,
+    pub permissions: HashSet<Permission>,
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub namespaces: HashSet<String>,
+}
+
+/// A token as shown to clients: everything but the secret itself.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenInfo {
+    pub id: String,
+    /// The first characters of the token, to tell tokens apart.
+    pub masked: String,
+    pub user_id: String,
+    pub permissions: HashSet<Permission>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub scopes: HashMap<Permission, Vec<String>>,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    pub last_used: Option<u64>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub peer: bool,
+}
+
+impl From<&AuthToken> for TokenInfo {
+    fn from(token: &AuthToken) -> Self {
+        TokenInfo {
+            id: token.id.clone(),
+            masked: auth::mask_token(&token.token),
+            user_id: token.user_id.clone(),
+            permissions: token.permissions.clone(),
+            scopes: token.scopes.clone(),
+            created_at: token.created_at,
+            expires_at: token.expires_at,
+            last_used: token.last_used,
+            peer: token.peer,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RateLimitInfo {
+    pub pattern: String,
+    pub max_signals: u32,
+    pub per_seconds: u64,
+}
+
+/// A named subscription whose position survives disconnects and restarts.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DurableSubscription {
+    pub name: String,
+    pub pattern: String,
+    pub user_id: String,
+    /// Id of the last acknowledged signal; later matching signals are kept for the consumer.
+    pub cursor: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DurableInfo {
+    pub name: String,
+    pub pattern: String,
+    pub user_id: String,
+    pub cursor: u64,
+    pub pending: usize,
+    pub dropped: u64,
+    pub attached: bool,
+}
+
+/// A signal a consumer failed to process too many times, set aside for inspection.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeadLetter {
+    /// Id within the dead-letter queue, unrelated to the signal's id.
+    pub id: u64,
+    pub signal: PersistentSignal,
+    /// Pattern and user of the subscription that gave up on the signal.
+    pub pattern: String,
+    pub user_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub durable: Option<String>,
+    pub failures: u32,
+    /// Why the last delivery failed.
+    pub reason: String,
+    pub dead_lettered_at: u64,
+}
+
+/// A signal held by the daemon until it is due.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScheduledSignal {
+    pub id: u64,
+    pub signal: Signal,
+    pub ttl: Option<u64>,
+    /// Unix time the signal is published at.
+    pub due_at: u64,
+    pub user_id: String,
+    pub scheduled_at: u64,
+    /// Whether the emitter could let a critical signal skip rate limits.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub bypass_rate_limits: bool,
+}
+
+/// What a cron job does about runs it missed, e.g. while the daemon was down.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum MissedRuns {
+    /// publish one catch-up run, however many were missed
+    #[default]
+    Once,
+    /// wait for the next scheduled run
+    Skip,
+}
+
+/// What a cron job publishes and when.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CronSpec {
+    pub signal: String,
+    /// Five-field cron expression in the daemon's local time zone.
+    pub schedule: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+    #[serde(default, skip_serializing_if = "Priority::is_normal")]
+    pub priority: Priority,
+    #[serde(default)]
+    pub missed: MissedRuns,
+}
+
+impl fmt::Display for MissedRuns {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MissedRuns::Once => "once",
+            MissedRuns::Skip => "skip",
+        })
+    }
+}
+
+/// A signal the daemon publishes on a cron schedule.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CronJob {
+    pub id: u64,
+    #[serde(flatten)]
+    pub spec: CronSpec,
+    #[serde(default)]
+    pub paused: bool,
+    pub user_id: String,
+    /// Unix time of the next run.
+    pub next_run: u64,
+    pub last_run: Option<u64>,
+}
+
+/// What the daemon does when a subscriber's queue is full.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// wait for room up to the block timeout, then drop the signal
+    #[default]
+    Block,
+    /// discard the oldest queued signal of the lowest priority to make room
+    DropOldest,
+    /// discard the incoming signal
+    DropNewest,
+    /// end the subscription
+    Disconnect,
+}
+
+/// How a consumer group picks the member that receives a signal.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    /// take turns between the members
+    #[default]
+    RoundRobin,
+    /// pick the member with the fewest queued signals
+    LeastBusy,
+}
+
+/// How urgently a signal should be delivered. Queued signals are handed to
+/// a subscriber highest priority first.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+    /// may skip rate limits when emitted with the rate_limit permission
+    Critical,
+}
+
+impl Priority {
+    pub fn is_normal(&self) -> bool {
+        *self == Priority::Normal
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+            Priority::Critical => "critical",
+        })
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AuthRequest {
     pub token: String,
@@ -31,8 +333,66 @@ pub struct Signal {
     pub name: String,
     pub payload: Option<serde_json::Value>,
     pub timestamp: u64,
+    /// Set on requests: the signal name a reply should be emitted as.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    /// Pairs a reply with its request; copied from the request into the reply.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Priority::is_normal")]
+    pub priority: Priority,
+    /// Who emitted the signal. Stamped by the daemon; whatever a client
+    /// sends here is replaced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<Sender>,
+}
+
+/// The identity behind a published signal.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Sender {
+    pub user_id: String,
+    /// Id of the token the signal was emitted with; `None` for signals the
+    /// daemon emits itself, such as cron jobs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<String>,
+    /// Process and user id of the emitting client, from the socket's peer
+    /// credentials.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pid: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
 }
 
+impl Sender {
+    pub fn new(token: &AuthToken, peer: Option<UCred>) -> Self {
+        Sender {
+            user_id: token.user_id.clone(),
+            token_id: Some(token.id.clone()),
+            pid: peer.and_then(|peer| peer.pid()),
+            uid: peer.map(|peer| peer.uid()),
+        }
+    }
+}
+
+impl fmt::Display for Sender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.user_id)?;
+        if let Some(token_id) = &self.token_id {
+            write!(f, " token={}", token_id)?;
+        }
+        if let Some(pid) = self.pid {
+            write!(f, " pid={}", pid)?;
+        }
+        if let Some(uid) = self.uid {
+            write!(f, " uid={}", uid)?;
+        }
+        Ok(())
+    }
+}
+
+/// Prefix of the signal names requests are answered on.
+pub const REPLY_PREFIX: &str = "_reply.";
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PersistentSignal {
     pub signal: Signal,
@@ -40,6 +400,14 @@ pub struct PersistentSignal {
     pub ttl: Option<u64>, 
 }
 
+impl PersistentSignal {
+    /// Whether the signal's TTL, if any, has not passed at `now`. TTLs come
+    /// from clients, so a huge one means the signal never expires.
+    pub fn is_live(&self, now: u64) -> bool {
+        self.ttl.is_none_or(|ttl| self.signal.timestamp.saturating_add(ttl) > now)
+    }
+}
+
 impl Signal {
     pub fn new(name: String, payload: Option<String>) -> anyhow::Result<Self> {
         let payload_value = match payload {
@@ -53,17 +421,65 @@ impl Signal {
             timestamp: SystemTime::now()
                 .duration_since(UNIX_EPOCH)?
                 .as_secs(),
+            reply_to: None,
+            correlation_id: None,
+            priority: Priority::Normal,
+            sender: None,
+        })
+    }
+
+    /// Whether the signal is a request that can be answered: its `reply_to`
+    /// names a reply signal rather than an arbitrary one.
+    pub fn is_request(&self) -> bool {
+        self.reply_to.as_deref().is_some_and(|reply_to| reply_to.starts_with(REPLY_PREFIX))
+    }
+
+    /// Builds the reply to a request, or `None` if the signal is not one.
+    ///
+    /// The reply is emitted with the server's token, so a requester must not
+    /// be able to pick what it emits: only `_reply.*` names are answered and
+    /// replies always have normal priority.
+    pub fn reply(&self, payload: Option<serde_json::Value>) -> Option<Signal> {
+        if !self.is_request() {
+            return None;
+        }
+        Some(Signal {
+            name: self.reply_to.clone()?,
+            payload,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs(),
+            reply_to: None,
+            correlation_id: self.correlation_id.clone(),
+            priority: Priority::Normal,
+            sender: None,
         })
     }
 }
 
-pub fn pattern_match(pattern: &str, signal_name: &str) -> bool {
-    if pattern.ends_with(":*") {
-        let prefix = &pattern[..pattern.len() - 2];
-        signal_name.starts_with(prefix)
-    } else if pattern == "*" {
-        true
-    } else {
-        pattern == signal_name
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(reply_to: &str) -> Signal {
+        let mut signal = Signal::new("build.status".to_string(), None).unwrap();
+        signal.reply_to = Some(reply_to.to_string());
+        signal.correlation_id = Some("c1".to_string());
+        signal.priority = Priority::Critical;
+        signal
+    }
+
+    #[test]
+    fn reply_goes_to_reply_name_with_normal_priority() {
+        let reply = request("_reply.abc").reply(Some(serde_json::json!(1))).unwrap();
+        assert_eq!(reply.name, "_reply.abc");
+        assert_eq!(reply.correlation_id.as_deref(), Some("c1"));
+        assert_eq!(reply.priority, Priority::Normal);
+        assert!(reply.reply_to.is_none());
+    }
+
+    #[test]
+    fn reply_to_other_names_is_refused() {
+        assert!(request("deploy:start").reply(None).is_none());
+        assert!(!request("deploy:start").is_request());
+        assert!(Signal::new("plain".to_string(), None).unwrap().reply(None).is_none());
     }
 }
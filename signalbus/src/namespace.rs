@@ -0,0 +1,57 @@
+use crate::pattern::{Pattern, Segment, SEPARATORS};
+use std::collections::HashSet;
+
+/// First segment of private signal names: `user.<id>.<name>` (or
+/// `user.<id>:<name>`) belongs to the user `<id>`. Two-segment names such as
+/// `user.created` stay public.
+pub const PRIVATE_ROOT: &str = "user";
+
+/// The user whose private namespace a signal name is in, if any.
+pub fn owner(name: &str) -> Option<&str> {
+    let mut segments = name.split(SEPARATORS);
+    if segments.next() != Some(PRIVATE_ROOT) {
+        return None;
+    }
+    let owner = segments.next()?;
+    segments.next()?;
+    Some(owner)
+}
+
+/// The user whose private namespace a pattern names explicitly, as in
+/// `user.alice.**`. Wildcards in place of the user match many namespaces
+/// and are filtered signal by signal instead.
+pub fn pattern_owner(pattern: &Pattern) -> Option<&str> {
+    match pattern.segments() {
+        [Segment::Literal(root), Segment::Literal(user), _, ..] if root == PRIVATE_ROOT => Some(user),
+        _ => None,
+    }
+}
+
+/// Which private namespaces a caller may read and write: their own, those
+/// an admin granted them, or all of them for admins.
+#[derive(Debug, Clone)]
+pub struct Access {
+    user_id: String,
+    admin: bool,
+    granted: HashSet<String>,
+}
+
+impl Access {
+    pub fn new(user_id: String, admin: bool, granted: HashSet<String>) -> Self {
+        Access { user_id, admin, granted }
+    }
+
+    pub fn can_access(&self, name: &str) -> bool {
+        owner(name).is_none_or(|owner| self.owns(owner))
+    }
+
+    /// False for patterns naming someone else's namespace; other patterns
+    /// are allowed and their private matches filtered with `can_access`.
+    pub fn can_access_pattern(&self, pattern: &Pattern) -> bool {
+        pattern_owner(pattern).is_none_or(|owner| self.owns(owner))
+    }
+
+    fn owns(&self, owner: &str) -> bool {
+        self.admin || owner == self.user_id || self.granted.contains(owner)
+    }
+}
@@ -0,0 +1,349 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+/// Characters that split signal names and patterns into segments. Both are
+/// equivalent, so `user:created` and `user.created` name the same signal.
+pub const SEPARATORS: [char; 2] = [':', '.'];
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum PatternError {
+    #[error("Pattern is empty")]
+    Empty,
+    #[error("Pattern '{0}' has an empty segment")]
+    EmptySegment(String),
+    #[error("Pattern '{0}' uses '**' inside a segment; it must stand alone between separators")]
+    MisplacedGlobstar(String),
+    #[error("Pattern '{pattern}' contains the invalid character {character:?}")]
+    InvalidCharacter { pattern: String, character: char },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Segment {
+    /// Matches exactly this text.
+    Literal(String),
+    /// Matches one segment; `*` spans any run of characters and `?` one character.
+    Glob(Vec<GlobToken>),
+    /// `**`: matches zero or more whole segments.
+    Any,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum GlobToken {
+    Char(char),
+    Star,
+    Question,
+}
+
+/// A compiled, validated signal pattern.
+///
+/// Patterns are split into segments on `:` and `.`. Within a segment `*`
+/// matches any run of characters and `?` a single character; neither crosses
+/// a separator. A segment that is exactly `**` matches any number of
+/// segments, including none. The pattern `*` on its own matches every signal.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    pub fn parse(source: &str) -> Result<Self, PatternError> {
+        if source.is_empty() {
+            return Err(PatternError::Empty);
+        }
+        if let Some(character) = source.chars().find(|c| c.is_whitespace() || c.is_control() || *c == '|') {
+            return Err(PatternError::InvalidCharacter { pattern: source.to_string(), character });
+        }
+
+        let segments = if source == "*" {
+            vec![Segment::Any]
+        } else {
+            source.split(SEPARATORS)
+                .map(|segment| compile_segment(source, segment))
+                .collect::<Result<_, _>>()?
+        };
+
+        Ok(Pattern { source: source.to_string(), segments })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    pub(crate) fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn matches(&self, signal_name: &str) -> bool {
+        let names: Vec<&str> = signal_name.split(SEPARATORS).collect();
+        match_segments(&self.segments, &names, &mut HashSet::new())
+    }
+
+    /// Whether every name `other` matches is matched by this pattern too.
+    ///
+    /// Conservative for globs within a segment: `ci:*` covers `ci:b*`, but
+    /// `ci:a*` is not recognised as covering `ci:ab*`.
+    pub fn covers(&self, other: &Pattern) -> bool {
+        covers_segments(&self.segments, &other.segments, &mut HashSet::new())
+    }
+}
+
+fn compile_segment(source: &str, segment: &str) -> Result<Segment, PatternError> {
+    if segment.is_empty() {
+        return Err(PatternError::EmptySegment(source.to_string()));
+    }
+    if segment == "**" {
+        return Ok(Segment::Any);
+    }
+    if segment.contains("**") {
+        return Err(PatternError::MisplacedGlobstar(source.to_string()));
+    }
+    if !segment.contains(['*', '?']) {
+        return Ok(Segment::Literal(segment.to_string()));
+    }
+
+    let tokens = segment.chars()
+        .map(|c| match c {
+            '*' => GlobToken::Star,
+            '?' => GlobToken::Question,
+            c => GlobToken::Char(c),
+        })
+        .collect();
+    Ok(Segment::Glob(tokens))
+}
+
+/// `failed` remembers, by the lengths of the remaining slices, the suffixes
+/// already known not to match, so each `**` fanning out stays polynomial.
+fn match_segments(segments: &[Segment], names: &[&str], failed: &mut HashSet<(usize, usize)>) -> bool {
+    if failed.contains(&(segments.len(), names.len())) {
+        return false;
+    }
+    let matched = match segments.split_first() {
+        None => names.is_empty(),
+        Some((Segment::Any, rest)) => (0..=names.len()).any(|skip| match_segments(rest, &names[skip..], failed)),
+        Some((segment, rest)) => match names.split_first() {
+            Some((name, names)) => match_segment(segment, name) && match_segments(rest, names, failed),
+            None => false,
+        },
+    };
+    if !matched {
+        failed.insert((segments.len(), names.len()));
+    }
+    matched
+}
+
+/// Memoized like `match_segments`.
+fn covers_segments(outer: &[Segment], inner: &[Segment], failed: &mut HashSet<(usize, usize)>) -> bool {
+    if failed.contains(&(outer.len(), inner.len())) {
+        return false;
+    }
+    let covered = match (outer.split_first(), inner.split_first()) {
+        (None, None) => true,
+        (None, Some(_)) => false,
+        // `**` either ends here or absorbs the next inner segment, `**` included.
+        (Some((Segment::Any, rest)), _) => {
+            covers_segments(rest, inner, failed) || (!inner.is_empty() && covers_segments(outer, &inner[1..], failed))
+        }
+        (Some(_), None) | (Some(_), Some((Segment::Any, _))) => false,
+        (Some((segment, rest)), Some((other, other_rest))) => {
+            covers_segment(segment, other) && covers_segments(rest, other_rest, failed)
+        }
+    };
+    if !covered {
+        failed.insert((outer.len(), inner.len()));
+    }
+    covered
+}
+
+fn covers_segment(outer: &Segment, inner: &Segment) -> bool {
+    match (outer, inner) {
+        (_, Segment::Literal(name)) => match_segment(outer, name),
+        (Segment::Glob(tokens), Segment::Glob(other)) => {
+            tokens == other || tokens.iter().all(|token| *token == GlobToken::Star)
+        }
+        _ => false,
+    }
+}
+
+pub(crate) fn match_segment(segment: &Segment, name: &str) -> bool {
+    match segment {
+        Segment::Literal(literal) => literal == name,
+        Segment::Glob(tokens) => match_glob(tokens, &name.chars().collect::<Vec<_>>()),
+        Segment::Any => true,
+    }
+}
+
+/// Iterative wildcard match that backtracks to the most recent `*` only.
+fn match_glob(tokens: &[GlobToken], name: &[char]) -> bool {
+    let (mut t, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        match tokens.get(t) {
+            Some(GlobToken::Star) => {
+                star = Some((t, n));
+                t += 1;
+            }
+            Some(GlobToken::Question) => {
+                t += 1;
+                n += 1;
+            }
+            Some(GlobToken::Char(c)) if *c == name[n] => {
+                t += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_t, star_n)) => {
+                    t = star_t + 1;
+                    n = star_n + 1;
+                    star = Some((star_t, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    tokens[t..].iter().all(|token| matches!(token, GlobToken::Star))
+}
+
+impl FromStr for Pattern {
+    type Err = PatternError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Pattern::parse(s)
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Eq for Pattern {}
+
+impl Hash for Pattern {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.source.hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(source: &str) -> Pattern {
+        Pattern::parse(source).unwrap()
+    }
+
+    #[test]
+    fn literal_matches_exactly() {
+        let p = pattern("build:done");
+        assert!(p.matches("build:done"));
+        assert!(!p.matches("build:done:late"));
+        assert!(!p.matches("build"));
+        assert!(!p.matches("build:don"));
+    }
+
+    #[test]
+    fn separators_are_equivalent() {
+        assert!(pattern("user:created").matches("user.created"));
+        assert!(pattern("user.created").matches("user:created"));
+        assert!(pattern("system.cpu:*").matches("system:cpu.high"));
+    }
+
+    #[test]
+    fn star_stays_within_one_segment() {
+        let p = pattern("build:*");
+        assert!(p.matches("build:started"));
+        assert!(!p.matches("build:step:done"));
+        assert!(!p.matches("build"));
+        assert!(!p.matches("deploy:started"));
+
+        let p = pattern("build:st*d");
+        assert!(p.matches("build:started"));
+        assert!(p.matches("build:std"));
+        assert!(!p.matches("build:stopped:x"));
+        assert!(!p.matches("build:stop"));
+    }
+
+    #[test]
+    fn lone_star_matches_everything() {
+        let p = pattern("*");
+        assert!(p.matches("a"));
+        assert!(p.matches("a:b.c"));
+    }
+
+    #[test]
+    fn globstar_matches_any_number_of_segments() {
+        let p = pattern("build:**");
+        assert!(p.matches("build"));
+        assert!(p.matches("build:done"));
+        assert!(p.matches("build:step:done"));
+        assert!(!p.matches("deploy:done"));
+
+        let p = pattern("**:done");
+        assert!(p.matches("done"));
+        assert!(p.matches("build:step:done"));
+        assert!(!p.matches("build:step"));
+
+        let p = pattern("a:**:z");
+        assert!(p.matches("a:z"));
+        assert!(p.matches("a:b:c:z"));
+        assert!(!p.matches("a:b:c"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_character() {
+        let p = pattern("job:?");
+        assert!(p.matches("job:1"));
+        assert!(!p.matches("job:12"));
+        assert!(!p.matches("job:"));
+        assert!(pattern("job:?*").matches("job:12"));
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        assert_eq!(Pattern::parse(""), Err(PatternError::Empty));
+        assert_eq!(Pattern::parse("a::b"), Err(PatternError::EmptySegment("a::b".to_string())));
+        assert_eq!(Pattern::parse("a."), Err(PatternError::EmptySegment("a.".to_string())));
+        assert_eq!(Pattern::parse("a:b**"), Err(PatternError::MisplacedGlobstar("a:b**".to_string())));
+        assert!(matches!(Pattern::parse("a b"), Err(PatternError::InvalidCharacter { character: ' ', .. })));
+        assert!(matches!(Pattern::parse("a|b"), Err(PatternError::InvalidCharacter { character: '|', .. })));
+    }
+
+    #[test]
+    fn covers_patterns_it_matches_every_name_of() {
+        assert!(pattern("ci:**").covers(&pattern("ci:build.*")));
+        assert!(pattern("ci:**").covers(&pattern("ci:**")));
+        assert!(pattern("**").covers(&pattern("*")));
+        assert!(pattern("ci:*").covers(&pattern("ci:b*")));
+        assert!(pattern("ci:*").covers(&pattern("ci:build")));
+        assert!(pattern("ci:build").covers(&pattern("ci.build")));
+
+        assert!(!pattern("ci:**").covers(&pattern("*")));
+        assert!(!pattern("ci:*").covers(&pattern("ci:**")));
+        assert!(!pattern("ci:*").covers(&pattern("ci:a:b")));
+        assert!(!pattern("ci:build").covers(&pattern("ci:*")));
+        // Conservative for partial globs.
+        assert!(!pattern("ci:a*").covers(&pattern("ci:ab*")));
+    }
+
+    #[test]
+    fn many_globstars_do_not_blow_up() {
+        let globstars = pattern(&vec!["**"; 30].join(":"));
+        let anchored = pattern(&format!("{}:z", vec!["**"; 30].join(":")));
+        let name = vec!["a"; 60].join(":");
+        assert!(globstars.matches(&name));
+        assert!(!anchored.matches(&name));
+        assert!(!anchored.covers(&pattern(&vec!["*"; 60].join(":"))));
+    }
+}
@@ -0,0 +1,194 @@
+use crate::models::Permission;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CStr, CString};
+use std::path::Path;
+use tokio::fs;
+use tokio::net::unix::UCred;
+
+/// Largest buffer handed to the `_r` lookups before giving up on an entry.
+const MAX_LOOKUP_BUFFER: usize = 1 << 20;
+/// Most groups read for a single user.
+const MAX_GROUPS: usize = 1 << 16;
+
+/// Maps local Unix accounts to SignalBus users, so processes of a trusted
+/// account are authenticated by their socket peer credentials instead of a
+/// token. Keys are Unix user or group names, or numeric ids.
+///
+/// ```json
+/// {
+///   "users": { "alice": { "user": "alice" } },
+///   "groups": { "developers": { "user": "dev", "permissions": ["Read", "Write"] } }
+/// }
+/// ```
+#[derive(Deserialize, Debug, Default)]
+pub struct PeerAuth {
+    #[serde(default)]
+    users: HashMap<String, PeerMapping>,
+    #[serde(default)]
+    groups: HashMap<String, PeerMapping>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PeerMapping {
+    /// The SignalBus user the account acts as.
+    pub user: String,
+    /// Limits the connection to these of the user's permissions; all of
+    /// them when omitted.
+    #[serde(default)]
+    pub permissions: Option<HashSet<Permission>>,
+}
+
+/// A connecting Unix account as the system's name service reports it.
+#[derive(Debug, Default)]
+struct Account {
+    user: Option<String>,
+    /// The peer's group first, then the groups the user is a member of.
+    groups: Vec<(u32, Option<String>)>,
+}
+
+impl PeerAuth {
+    pub async fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path).await
+            .with_context(|| format!("Failed to read peer auth config {}", path.display()))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Invalid peer auth config {}", path.display()))
+    }
+
+    /// The mapping for a connecting process. Its Unix user is looked up
+    /// first, then its primary group, then the groups listing the user as a
+    /// member, in the order the name service returns them. The account is
+    /// looked up on every connection, so removing a user from a group takes
+    /// effect for their next connection.
+    pub async fn resolve(&self, peer: &UCred) -> Option<&PeerMapping> {
+        let account = account(peer.uid(), peer.gid()).await;
+
+        let uid = peer.uid().to_string();
+        if let Some(mapping) = self.users.get(&uid).or_else(|| account.user.as_deref().and_then(|name| self.users.get(name))) {
+            return Some(mapping);
+        }
+        account.groups.iter().find_map(|(gid, name)| {
+            self.groups.get(&gid.to_string()).or_else(|| name.as_deref().and_then(|name| self.groups.get(name)))
+        })
+    }
+
+}
+
+/// Looks the account up through NSS, so LDAP or systemd users resolve like
+/// local ones. Lookups can block, so they run off the runtime.
+async fn account(uid: u32, gid: u32) -> Account {
+    match tokio::task::spawn_blocking(move || Account::lookup(uid, gid)).await {
+        Ok(account) => account,
+        Err(e) => {
+            eprintln!("Failed to look up Unix account {}: {}", uid, e);
+            Account::default()
+        }
+    }
+}
+
+impl Account {
+    fn lookup(uid: u32, gid: u32) -> Self {
+        let user = user_by_uid(uid);
+        let mut gids = vec![gid];
+        if let Some((name, primary)) = &user {
+            for member_of in group_list(name, *primary) {
+                if !gids.contains(&member_of) {
+                    gids.push(member_of);
+                }
+            }
+        }
+        Account {
+            user: user.map(|(name, _)| name),
+            groups: gids.into_iter().map(|gid| (gid, group_name(gid))).collect(),
+        }
+    }
+}
+
+/// Runs a `getpwuid_r` style lookup, growing the buffer while it is too
+/// small. `None` when the entry does not exist or cannot be read.
+fn with_buffer<T>(mut lookup: impl FnMut(&mut [libc::c_char]) -> Result<Option<T>, libc::c_int>) -> Option<T> {
+    let mut buffer = vec![0; 1024];
+    loop {
+        match lookup(&mut buffer) {
+            Ok(entry) => return entry,
+            Err(libc::ERANGE) if buffer.len() < MAX_LOOKUP_BUFFER => buffer.resize(buffer.len() * 2, 0),
+            Err(_) => return None,
+        }
+    }
+}
+
+/// The name and primary group of a user.
+fn user_by_uid(uid: u32) -> Option<(String, u32)> {
+    with_buffer(|buffer| {
+        // SAFETY: passwd is plain old data. On success its strings point into
+        // `buffer`, which is still alive when they are read.
+        let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        let code = unsafe { libc::getpwuid_r(uid, &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+        match code {
+            0 if result.is_null() => Ok(None),
+            0 => {
+                let name = unsafe { CStr::from_ptr(passwd.pw_name) }.to_string_lossy().into_owned();
+                Ok(Some((name, passwd.pw_gid)))
+            }
+            code => Err(code),
+        }
+    })
+}
+
+fn group_name(gid: u32) -> Option<String> {
+    with_buffer(|buffer| {
+        // SAFETY: as in `user_by_uid`.
+        let mut group: libc::group = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        let code = unsafe { libc::getgrgid_r(gid, &mut group, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+        match code {
+            0 if result.is_null() => Ok(None),
+            0 => Ok(Some(unsafe { CStr::from_ptr(group.gr_name) }.to_string_lossy().into_owned())),
+            code => Err(code),
+        }
+    })
+}
+
+/// The groups a user is a member of, including `primary`.
+fn group_list(user: &str, primary: u32) -> Vec<u32> {
+    let Ok(user) = CString::new(user) else {
+        return Vec::new();
+    };
+    let mut groups: Vec<libc::gid_t> = vec![0; 64];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        // SAFETY: `count` tells getgrouplist how many entries `groups` holds.
+        let code = unsafe { libc::getgrouplist(user.as_ptr(), primary, groups.as_mut_ptr(), &mut count) };
+        if code >= 0 {
+            groups.truncate(count.max(0) as usize);
+            return groups;
+        }
+        // Too small: glibc reports the size it needs in `count`.
+        let needed = (count.max(0) as usize).max(groups.len() * 2);
+        if needed > MAX_GROUPS {
+            return Vec::new();
+        }
+        groups.resize(needed, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_up_root() {
+        let account = Account::lookup(0, 0);
+        assert_eq!(account.user.as_deref(), Some("root"));
+        assert_eq!(account.groups.first(), Some(&(0, Some("root".to_string()))));
+    }
+
+    #[test]
+    fn unknown_accounts_keep_the_peer_group() {
+        let account = Account::lookup(u32::MAX - 1, u32::MAX - 1);
+        assert_eq!(account.user, None);
+        assert_eq!(account.groups, vec![(u32::MAX - 1, None)]);
+    }
+}
@@ -0,0 +1,365 @@
+use crate::models::{Balance, CronJob, CronSpec, DeadLetter, DurableInfo, OverflowPolicy, PersistentSignal, RateLimitInfo, ScheduledSignal, Signal, TokenInfo, UserInfo};
+use crate::store::StateSnapshot;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Version spoken after a successful `HELLO` handshake. Connections that
+/// start with anything else use the legacy `|`-delimited line protocol.
+pub const PROTOCOL_VERSION: u32 = 2;
+pub const HELLO_PREFIX: &str = "HELLO|";
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    /// Checks a token and makes it the default for later requests on the connection.
+    Authenticate { token: String },
+    Login { user_id: String, password: String },
+    Emit { signal: Signal, ttl: Option<u64> },
+    /// Publishes a signal at a unix time instead of now.
+    Schedule { signal: Signal, ttl: Option<u64>, at: u64 },
+    ScheduledSignals,
+    CancelScheduled { id: u64 },
+    /// Registers a signal the daemon publishes on a cron schedule.
+    AddCronJob {
+        #[serde(flatten)]
+        spec: CronSpec,
+    },
+    CronJobs,
+    RemoveCronJob { id: u64 },
+    PauseCronJob { id: u64 },
+    ResumeCronJob { id: u64 },
+    /// Starts a subscription; its signals arrive as events on the same connection.
+    Listen {
+        pattern: String,
+        #[serde(flatten)]
+        options: ListenOptions,
+    },
+    Unsubscribe { subscription: u64 },
+    /// Lets a subscription with a `prefetch` window receive `count` more events.
+    Credit { subscription: u64, count: u32 },
+    /// Confirms a signal delivered to a subscription in ack mode.
+    Ack { subscription: u64, id: u64 },
+    /// Reports that a signal could not be processed; it is delivered again
+    /// until it failed more than `max_retries` times.
+    Nack {
+        subscription: u64,
+        id: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    ListDurables,
+    DeleteDurable { name: String },
+    DeadLetters,
+    DeadLetter { id: u64 },
+    /// Emits one dead letter, or all of them without an id, again.
+    ReplayDeadLetters { id: Option<u64> },
+    /// Deletes one dead letter, or all of them without an id.
+    PurgeDeadLetters { id: Option<u64> },
+    History { pattern: String, limit: usize },
+    SetRateLimit { pattern: String, max_signals: u32, per_seconds: u64 },
+    RateLimits,
+    CreateToken {
+        user_id: String,
+        permissions: Vec<String>,
+        /// `PERMISSION=PATTERN` entries limiting a permission to matching signals.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        scopes: Vec<String>,
+        expires_in: Option<u64>,
+    },
+    RevokeToken { token: String },
+    RevokeTokenId { id: String },
+    RevokeUserTokens { user_id: String },
+    ListTokens,
+    /// Describes the token the request was made with.
+    TokenInfo,
+    /// Extends the expiry of the token the request was made with to
+    /// `expires_in` seconds from now, an hour by default and at most.
+    RefreshToken { expires_in: Option<u64> },
+    AddUser { user_id: String, password: String, permissions: Vec<String> },
+    SetPassword { user_id: String, password: String },
+    ListUsers,
+    DeleteUser { user_id: String },
+    SetUserPermissions { user_id: String, permissions: Vec<String> },
+    GrantNamespace { user_id: String, owner: String },
+    RevokeNamespace { user_id: String, owner: String },
+    ExportState,
+    ImportState { snapshot: StateSnapshot },
+}
+
+/// Optional settings of a `listen` request; every field may be omitted.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ListenOptions {
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
+    /// How long the `block` policy waits for room before dropping, in milliseconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_timeout_ms: Option<u64>,
+    /// Events sent before the client must grant more with `credit`. Without
+    /// it events are written as fast as the socket accepts them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefetch: Option<u32>,
+    /// Replays history from this signal id on before live delivery.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from_id: Option<u64>,
+    /// Replays history emitted at or after this unix timestamp.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<u64>,
+    /// Replays at most this many of the most recent matching signals.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last: Option<usize>,
+    /// Every event must be acknowledged with `ack`; events neither acked nor
+    /// nacked within `ack_timeout_ms` are delivered again.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ack: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ack_timeout_ms: Option<u64>,
+    /// Redeliveries of a failing signal before it moves to the dead-letter queue.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_retries: Option<u32>,
+    /// Joins a consumer group: each matching signal goes to one member of the
+    /// group instead of all of them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// How the group picks a member; fixed by the member that creates it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub balance: Option<Balance>,
+    /// Attaches to the named durable subscription, creating it on first use.
+    /// Delivery resumes after its last acknowledged signal. Durable
+    /// subscriptions always use ack mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub durable: Option<String>,
+}
+
+impl ListenOptions {
+    pub fn replays(&self) -> bool {
+        self.from_id.is_some() || self.since.is_some() || self.last.is_some()
+    }
+
+    pub fn acks(&self) -> bool {
+        self.ack || self.durable.is_some()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Request {
+    pub id: u64,
+    /// Overrides the connection's token for this request only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    pub command: Command,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Reply {
+    Ok,
+    Token { token: String },
+    Listening { subscription: u64 },
+    History { signals: Vec<PersistentSignal> },
+    RateLimits { limits: Vec<RateLimitInfo> },
+    Durables { durables: Vec<DurableInfo> },
+    DeadLetters { dead_letters: Vec<DeadLetter> },
+    DeadLetter { dead_letter: Box<DeadLetter> },
+    Count { count: usize },
+    Scheduled { scheduled: Box<ScheduledSignal> },
+    ScheduledSignals { scheduled: Vec<ScheduledSignal> },
+    CronJob { job: Box<CronJob> },
+    CronJobs { jobs: Vec<CronJob> },
+    Users { users: Vec<UserInfo> },
+    Tokens { tokens: Vec<TokenInfo> },
+    TokenInfo { token: Box<TokenInfo> },
+    State { snapshot: StateSnapshot },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    Unauthorized,
+    InvalidCredentials,
+    RateLimited,
+    NotFound,
+    AlreadyExists,
+    InvalidRequest,
+    Internal,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ApiError { code, message: message.into() }
+    }
+
+    pub fn unauthorized() -> Self {
+        Self::new(ErrorCode::Unauthorized, "Authentication failed or insufficient permissions")
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::NotFound, message)
+    }
+
+    pub fn invalid(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::InvalidRequest, message)
+    }
+
+    pub fn internal(error: impl fmt::Display) -> Self {
+        Self::new(ErrorCode::Internal, error.to_string())
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Ok(Reply),
+    Error(ApiError),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Response { id: u64, outcome: Outcome },
+    /// A signal delivered to the subscription with the id from its `Listening` reply.
+    /// `dropped` counts signals discarded by its overflow policy since the previous event.
+    Event {
+        subscription: u64,
+        /// History id of the signal, usable as `from_id` to resume.
+        id: u64,
+        signal: Signal,
+        #[serde(default, skip_serializing_if = "is_zero")]
+        dropped: u64,
+        /// Set when an ack-mode subscription gets the signal again after a
+        /// nack or an ack timeout.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        redelivered: bool,
+    },
+    /// The daemon ended a subscription, e.g. because it overflowed under the
+    /// `disconnect` policy.
+    Closed { subscription: u64, dropped: u64 },
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+/// Writes one frame: a big-endian `u32` length followed by a JSON body.
+pub async fn write_frame<W, T>(writer: &mut W, value: &T) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let body = serde_json::to_vec(value)?;
+    if body.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame exceeds the size limit"));
+    }
+    writer.write_u32(body.len() as u32).await?;
+    writer.write_all(&body).await?;
+    writer.flush().await
+}
+
+/// Reads the body of one frame, returning `None` when the peer closed the
+/// connection cleanly between frames.
+pub async fn read_frame_bytes<R>(reader: &mut R) -> io::Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let len = match reader.read_u32().await {
+        Ok(len) => len as usize,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame exceeds the size limit"));
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).await?;
+    Ok(Some(body))
+}
+
+pub async fn read_frame<R, T>(reader: &mut R) -> io::Result<Option<T>>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    match read_frame_bytes(reader).await? {
+        Some(body) => Ok(Some(serde_json::from_slice(&body)?)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn frames_round_trip() {
+        let request = Request {
+            id: 7,
+            token: Some("secret".to_string()),
+            command: Command::Emit { signal: Signal::new("build:done".to_string(), Some("{\"ok\":true}".to_string())).unwrap(), ttl: Some(60) },
+        };
+        let response = ServerMessage::Response { id: 7, outcome: Outcome::Ok(Reply::Count { count: 3 }) };
+
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, &request).await.unwrap();
+        write_frame(&mut buffer, &response).await.unwrap();
+        assert_eq!(u32::from_be_bytes(buffer[..4].try_into().unwrap()) as usize, serde_json::to_vec(&request).unwrap().len());
+
+        let mut reader = buffer.as_slice();
+        let read: Request = read_frame(&mut reader).await.unwrap().unwrap();
+        assert_eq!(read.id, 7);
+        assert_eq!(read.token.as_deref(), Some("secret"));
+        match read.command {
+            Command::Emit { signal, ttl } => {
+                assert_eq!(signal.name, "build:done");
+                assert_eq!(signal.payload, Some(serde_json::json!({ "ok": true })));
+                assert_eq!(ttl, Some(60));
+            }
+            other => panic!("unexpected command {:?}", other),
+        }
+        match read_frame::<_, ServerMessage>(&mut reader).await.unwrap().unwrap() {
+            ServerMessage::Response { id: 7, outcome: Outcome::Ok(Reply::Count { count: 3 }) } => {}
+            other => panic!("unexpected message {:?}", other),
+        }
+        assert!(read_frame::<_, ServerMessage>(&mut reader).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn truncated_frame_is_an_error() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, &Reply::Ok).await.unwrap();
+        buffer.pop();
+        let error = read_frame::<_, Reply>(&mut buffer.as_slice()).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_is_rejected() {
+        let buffer = ((MAX_FRAME_LEN + 1) as u32).to_be_bytes();
+        let error = read_frame_bytes(&mut buffer.as_slice()).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn malformed_body_is_an_error() {
+        let mut buffer = 4u32.to_be_bytes().to_vec();
+        buffer.extend_from_slice(b"nope");
+        let error = read_frame::<_, Reply>(&mut buffer.as_slice()).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
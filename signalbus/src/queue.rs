@@ -0,0 +1,275 @@
+use crate::models::PersistentSignal;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// Why a signal could not be queued without waiting. A full queue hands
+/// the signal back so the caller can apply its overflow policy.
+#[derive(Debug)]
+pub enum TrySendError {
+    Full(Box<PersistentSignal>),
+    Closed,
+}
+
+/// The queue was closed; no more signals can be sent or received.
+#[derive(Debug)]
+pub struct Closed;
+
+struct Lanes {
+    /// One FIFO per priority, indexed by `Priority as usize`.
+    lanes: [VecDeque<PersistentSignal>; 4],
+    len: usize,
+    closed: bool,
+}
+
+impl Lanes {
+    fn pop_highest(&mut self) -> Option<PersistentSignal> {
+        let signal = self.lanes.iter_mut().rev().find_map(VecDeque::pop_front)?;
+        self.len -= 1;
+        Some(signal)
+    }
+
+    /// The oldest signal of the lowest priority, which is the least missed.
+    fn pop_lowest(&mut self) -> Option<PersistentSignal> {
+        let signal = self.lanes.iter_mut().find_map(VecDeque::pop_front)?;
+        self.len -= 1;
+        Some(signal)
+    }
+}
+
+struct Shared {
+    lanes: Mutex<Lanes>,
+    capacity: usize,
+    readable: Notify,
+    writable: Notify,
+}
+
+/// A bounded subscriber queue that hands out higher priority signals first
+/// and keeps signals of equal priority in order.
+///
+/// Dropping either side closes the queue. The receiver still drains what
+/// was queued before it sees the close.
+pub fn bounded(capacity: usize) -> (Sender, Receiver) {
+    let shared = Arc::new(Shared {
+        lanes: Mutex::new(Lanes { lanes: Default::default(), len: 0, closed: false }),
+        capacity,
+        readable: Notify::new(),
+        writable: Notify::new(),
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+pub struct Sender {
+    shared: Arc<Shared>,
+}
+
+impl Sender {
+    pub fn try_send(&self, signal: PersistentSignal) -> Result<(), TrySendError> {
+        let mut lanes = self.shared.lanes.lock().unwrap();
+        if lanes.closed {
+            return Err(TrySendError::Closed);
+        }
+        if lanes.len >= self.shared.capacity {
+            return Err(TrySendError::Full(Box::new(signal)));
+        }
+        lanes.lanes[signal.signal.priority as usize].push_back(signal);
+        lanes.len += 1;
+        drop(lanes);
+        self.shared.readable.notify_one();
+        Ok(())
+    }
+
+    /// Queues a signal even when full, displacing the oldest signal of the
+    /// lowest queued priority, which may be the new signal itself.
+    pub fn force_send(&self, signal: PersistentSignal) -> Result<Option<PersistentSignal>, Closed> {
+        let mut lanes = self.shared.lanes.lock().unwrap();
+        if lanes.closed {
+            return Err(Closed);
+        }
+        lanes.lanes[signal.signal.priority as usize].push_back(signal);
+        lanes.len += 1;
+        let displaced = if lanes.len > self.shared.capacity { lanes.pop_lowest() } else { None };
+        drop(lanes);
+        self.shared.readable.notify_one();
+        Ok(displaced)
+    }
+
+    /// Waits for room in the queue.
+    pub async fn send(&self, mut signal: PersistentSignal) -> Result<(), Closed> {
+        loop {
+            let writable = self.shared.writable.notified();
+            tokio::pin!(writable);
+            writable.as_mut().enable();
+
+            match self.try_send(signal) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Closed) => return Err(Closed),
+                Err(TrySendError::Full(returned)) => signal = *returned,
+            }
+            writable.await;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.lanes.lock().unwrap().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.lanes.lock().unwrap().closed
+    }
+
+    pub fn close(&self) {
+        close(&self.shared);
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        close(&self.shared);
+    }
+}
+
+pub struct Receiver {
+    shared: Arc<Shared>,
+}
+
+impl Receiver {
+    /// The highest priority queued signal. Cancel safe: a signal is only
+    /// taken from the queue when it is returned.
+    pub async fn recv(&self) -> Result<PersistentSignal, Closed> {
+        loop {
+            let readable = self.shared.readable.notified();
+            tokio::pin!(readable);
+            readable.as_mut().enable();
+
+            {
+                let mut lanes = self.shared.lanes.lock().unwrap();
+                if let Some(signal) = lanes.pop_highest() {
+                    drop(lanes);
+                    self.shared.writable.notify_one();
+                    return Ok(signal);
+                }
+                if lanes.closed {
+                    return Err(Closed);
+                }
+            }
+            readable.await;
+        }
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        close(&self.shared);
+    }
+}
+
+fn close(shared: &Shared) {
+    shared.lanes.lock().unwrap().closed = true;
+    shared.readable.notify_waiters();
+    shared.writable.notify_waiters();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Priority, Signal};
+    use std::time::Duration;
+
+    fn signal(id: u64, priority: Priority) -> PersistentSignal {
+        let mut signal = Signal::new(format!("test:{}", id), None).unwrap();
+        signal.priority = priority;
+        PersistentSignal { signal, id, ttl: None }
+    }
+
+    #[tokio::test]
+    async fn receives_highest_priority_first_and_fifo_within_a_priority() {
+        let (tx, rx) = bounded(10);
+        tx.try_send(signal(1, Priority::Low)).unwrap();
+        tx.try_send(signal(2, Priority::Normal)).unwrap();
+        tx.try_send(signal(3, Priority::Critical)).unwrap();
+        tx.try_send(signal(4, Priority::Normal)).unwrap();
+        tx.try_send(signal(5, Priority::High)).unwrap();
+
+        let mut order = Vec::new();
+        for _ in 0..5 {
+            order.push(rx.recv().await.unwrap().id);
+        }
+        assert_eq!(order, vec![3, 5, 2, 4, 1]);
+        assert!(tx.is_empty());
+    }
+
+    #[test]
+    fn try_send_hands_back_the_signal_when_full() {
+        let (tx, _rx) = bounded(1);
+        tx.try_send(signal(1, Priority::Normal)).unwrap();
+        match tx.try_send(signal(2, Priority::Critical)) {
+            Err(TrySendError::Full(returned)) => assert_eq!(returned.id, 2),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(tx.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn force_send_displaces_the_oldest_lowest_priority_signal() {
+        let (tx, rx) = bounded(2);
+        tx.try_send(signal(1, Priority::Normal)).unwrap();
+        tx.try_send(signal(2, Priority::Low)).unwrap();
+
+        let displaced = tx.force_send(signal(3, Priority::High)).unwrap();
+        assert_eq!(displaced.map(|ps| ps.id), Some(2));
+
+        // A new signal below everything queued is the one displaced.
+        let displaced = tx.force_send(signal(4, Priority::Low)).unwrap();
+        assert_eq!(displaced.map(|ps| ps.id), Some(4));
+
+        // Within a priority the oldest goes first.
+        let displaced = tx.force_send(signal(5, Priority::Normal)).unwrap();
+        assert_eq!(displaced.map(|ps| ps.id), Some(1));
+
+        assert_eq!(rx.recv().await.unwrap().id, 3);
+        assert_eq!(rx.recv().await.unwrap().id, 5);
+    }
+
+    #[tokio::test]
+    async fn send_waits_for_room() {
+        let (tx, rx) = bounded(1);
+        tx.try_send(signal(1, Priority::Normal)).unwrap();
+
+        let sender = tokio::spawn(async move {
+            tx.send(signal(2, Priority::Normal)).await.unwrap();
+            tx
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!sender.is_finished());
+
+        assert_eq!(rx.recv().await.unwrap().id, 1);
+        let tx = tokio::time::timeout(Duration::from_secs(1), sender).await.unwrap().unwrap();
+        assert_eq!(rx.recv().await.unwrap().id, 2);
+        assert!(tx.is_empty());
+    }
+
+    #[tokio::test]
+    async fn closing_drains_then_reports_closed() {
+        let (tx, rx) = bounded(2);
+        tx.try_send(signal(1, Priority::Normal)).unwrap();
+        drop(tx);
+        assert_eq!(rx.recv().await.unwrap().id, 1);
+        assert!(rx.recv().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn dropping_the_receiver_closes_the_sender() {
+        let (tx, rx) = bounded(1);
+        tx.try_send(signal(1, Priority::Normal)).unwrap();
+        drop(rx);
+        assert!(tx.is_closed());
+        assert!(matches!(tx.try_send(signal(2, Priority::Normal)), Err(TrySendError::Closed)));
+        assert!(tx.force_send(signal(3, Priority::Normal)).is_err());
+        assert!(tx.send(signal(4, Priority::Normal)).await.is_err());
+    }
+}
@@ -0,0 +1,107 @@
+use crate::models::ScheduledSignal;
+use crate::json_file::JsonFile;
+use anyhow::Result;
+use std::path::Path;
+
+const SCHEDULE_FILE: &str = "schedule.json";
+/// Scheduled signals held at once; scheduling more is refused.
+pub const SCHEDULE_LIMIT: usize = 10_000;
+
+/// Signals waiting to be published, soonest first.
+pub struct Schedule {
+    entries: Vec<ScheduledSignal>,
+    next_id: u64,
+    file: JsonFile,
+}
+
+impl Schedule {
+    pub fn in_memory() -> Self {
+        Schedule { entries: Vec::new(), next_id: 1, file: JsonFile::in_memory() }
+    }
+
+    pub async fn open(dir: &Path) -> Result<Self> {
+        let (file, mut entries): (_, Vec<ScheduledSignal>) = JsonFile::open(dir, SCHEDULE_FILE).await?;
+        entries.sort_by_key(|entry| (entry.due_at, entry.id));
+        let next_id = entries.iter().map(|entry| entry.id + 1).max().unwrap_or(1);
+        Ok(Schedule { entries, next_id, file })
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.entries.len() >= SCHEDULE_LIMIT
+    }
+
+    /// Adds a scheduled signal, assigning its id.
+    pub fn push(&mut self, mut scheduled: ScheduledSignal) -> ScheduledSignal {
+        scheduled.id = self.next_id;
+        self.next_id += 1;
+        let position = self.entries.partition_point(|entry| entry.due_at <= scheduled.due_at);
+        self.entries.insert(position, scheduled.clone());
+        scheduled
+    }
+
+    pub fn list(&self) -> Vec<ScheduledSignal> {
+        self.entries.clone()
+    }
+
+    pub fn get(&self, id: u64) -> Option<&ScheduledSignal> {
+        self.entries.iter().find(|entry| entry.id == id)
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<ScheduledSignal> {
+        let position = self.entries.iter().position(|entry| entry.id == id)?;
+        Some(self.entries.remove(position))
+    }
+
+    /// Moves a signal's due time, e.g. to retry it after a failed publish.
+    pub fn postpone(&mut self, id: u64, due_at: u64) {
+        if let Some(mut scheduled) = self.remove(id) {
+            scheduled.due_at = due_at;
+            let position = self.entries.partition_point(|entry| entry.due_at <= due_at);
+            self.entries.insert(position, scheduled);
+        }
+    }
+
+    /// When the soonest signal is due.
+    pub fn next_due(&self) -> Option<u64> {
+        self.entries.first().map(|entry| entry.due_at)
+    }
+
+    /// Signals due at `now`, soonest first. They stay scheduled until removed.
+    pub fn due(&self, now: u64) -> Vec<ScheduledSignal> {
+        self.entries.iter().take_while(|entry| entry.due_at <= now).cloned().collect()
+    }
+
+    pub async fn save(&self) -> Result<()> {
+        self.file.save(&self.entries).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Signal;
+
+    fn scheduled(due_at: u64) -> ScheduledSignal {
+        ScheduledSignal {
+            id: 0,
+            signal: Signal::new("test.scheduled".to_string(), None).unwrap(),
+            ttl: None,
+            due_at,
+            user_id: "tester".to_string(),
+            scheduled_at: 0,
+            bypass_rate_limits: false,
+        }
+    }
+
+    #[test]
+    fn postponing_keeps_the_id_and_due_order() {
+        let mut schedule = Schedule::in_memory();
+        let first = schedule.push(scheduled(10)).id;
+        let second = schedule.push(scheduled(20)).id;
+
+        schedule.postpone(first, 30);
+        assert_eq!(schedule.due(25).iter().map(|entry| entry.id).collect::<Vec<_>>(), vec![second]);
+        assert_eq!(schedule.get(first).unwrap().due_at, 30);
+        assert_eq!(schedule.next_due(), Some(20));
+    }
+}
@@ -0,0 +1,911 @@
+use crate::daemon::{DaemonState, SubscriberHandle};
+use crate::durable::{Attachment, Detached};
+use crate::namespace::Access;
+use crate::models::{parse_permissions, parse_scopes, DeadLetter, Permission, PersistentSignal, Sender, TokenInfo};
+use crate::pattern::Pattern;
+use crate::protocol::{
+    read_frame_bytes, write_frame, ApiError, Command, ErrorCode, ListenOptions, Outcome, Reply, Request,
+    ServerMessage,
+};
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::net::unix::{OwnedWriteHalf, UCred};
+use tokio::sync::{mpsc, AcquireError, Semaphore, SemaphorePermit};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+const OUTBOUND_CAPACITY: usize = 256;
+/// How long an ack-mode subscription has to acknowledge a signal before it
+/// is delivered again, unless `ListenOptions::ack_timeout_ms` says otherwise.
+pub const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_secs(30);
+/// Redeliveries of a failing signal before it is dead-lettered, unless
+/// `ListenOptions::max_retries` says otherwise.
+pub const DEFAULT_MAX_RETRIES: u32 = 5;
+
+/// Per-connection state: the default token and the live subscriptions, each
+/// forwarded to the connection's writer by its own task. Subscription ids are
+/// the daemon's, so they are unsubscribed there when the connection ends.
+struct Session {
+    token: Option<String>,
+    /// Credentials of the connected process, stamped on the signals it emits.
+    peer: Option<UCred>,
+    subscriptions: HashMap<u64, Forwarder>,
+}
+
+struct Forwarder {
+    task: JoinHandle<()>,
+    /// Remaining prefetch window; `None` when the client did not ask for one.
+    credit: Option<Arc<Semaphore>>,
+    /// Acks and nacks for the task; `None` unless the subscription uses ack mode.
+    settle: Option<mpsc::UnboundedSender<Settlement>>,
+}
+
+/// Serves a connection that negotiated the framed protocol. `leftover` holds
+/// bytes the handshake reader buffered past the `HELLO` line.
+///
+/// Requests are handled in order while events of any number of subscriptions
+/// are interleaved with the responses through a single writer task.
+pub async fn serve(stream: UnixStream, leftover: Vec<u8>, state: Arc<DaemonState>) -> Result<()> {
+    let peer = stream.peer_cred().ok();
+    let peer_token = match peer {
+        Some(peer) => state.peer_token(peer).await,
+        None => None,
+    };
+    let (read_half, write_half) = stream.into_split();
+    let mut reader = BufReader::new(Cursor::new(leftover).chain(read_half));
+
+    let (outbound, outbound_rx) = mpsc::channel(OUTBOUND_CAPACITY);
+    let writer = tokio::spawn(write_messages(write_half, outbound_rx));
+
+    let mut session = Session {
+        token: peer_token.clone(),
+        peer,
+        subscriptions: HashMap::new(),
+    };
+
+    let result = session.run(&state, &mut reader, &outbound).await;
+
+    for (subscription, forwarder) in session.subscriptions.drain() {
+        forwarder.task.abort();
+        state.unsubscribe(subscription).await;
+    }
+    if let Some(token) = peer_token {
+        state.release_peer_token(&token).await;
+    }
+    drop(outbound);
+    let _ = writer.await;
+    result
+}
+
+async fn write_messages(mut writer: OwnedWriteHalf, mut outbound: mpsc::Receiver<ServerMessage>) {
+    while let Some(message) = outbound.recv().await {
+        if let Err(e) = write_frame(&mut writer, &message).await {
+            eprintln!("Write error: {}", e);
+            break;
+        }
+    }
+}
+
+impl Session {
+    async fn run<R>(
+        &mut self,
+        state: &Arc<DaemonState>,
+        reader: &mut R,
+        outbound: &mpsc::Sender<ServerMessage>,
+    ) -> Result<()>
+    where
+        R: AsyncRead + Unpin,
+    {
+        while let Some(body) = read_frame_bytes(reader).await? {
+            let request: Request = match serde_json::from_slice(&body) {
+                Ok(request) => request,
+                Err(e) => {
+                    let outcome = Outcome::Error(ApiError::invalid(format!("Malformed request: {}", e)));
+                    outbound.send(ServerMessage::Response { id: 0, outcome }).await?;
+                    continue;
+                }
+            };
+
+            let token = request.token.or_else(|| self.token.clone());
+
+            if let Command::Listen { pattern, options } = request.command {
+                self.listen(state, request.id, token.as_deref(), pattern, options, outbound).await?;
+                continue;
+            }
+
+            let outcome = match self.dispatch(state, token.as_deref(), request.command).await {
+                Ok(reply) => Outcome::Ok(reply),
+                Err(e) => Outcome::Error(e),
+            };
+            outbound.send(ServerMessage::Response { id: request.id, outcome }).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Registers a subscription. The response is queued before the forwarding
+    /// task starts so a client never sees events for an unknown subscription.
+    async fn listen(
+        &mut self,
+        state: &Arc<DaemonState>,
+        id: u64,
+        token: Option<&str>,
+        pattern: String,
+        options: ListenOptions,
+        outbound: &mpsc::Sender<ServerMessage>,
+    ) -> Result<()> {
+        let (subscription, source, user_id) = match start_listening(state, token, &pattern, &options).await {
+            Ok(started) => started,
+            Err(e) => {
+                outbound.send(ServerMessage::Response { id, outcome: Outcome::Error(e) }).await?;
+                return Ok(());
+            }
+        };
+
+        let reply = Reply::Listening { subscription };
+        outbound.send(ServerMessage::Response { id, outcome: Outcome::Ok(reply) }).await?;
+
+        let credit = options.prefetch.map(|prefetch| Arc::new(Semaphore::new(prefetch as usize)));
+        let (settle, settlements) = mpsc::unbounded_channel();
+        let unacked = options.acks().then(|| Unacked::new(
+            options.ack_timeout_ms.map(Duration::from_millis).unwrap_or(DEFAULT_ACK_TIMEOUT),
+            options.max_retries.unwrap_or(DEFAULT_MAX_RETRIES),
+        ));
+        let forwarding = Forwarding {
+            state: state.clone(),
+            subscription,
+            consumer: Consumer { pattern, user_id, durable: options.durable.clone() },
+            source,
+            unacked,
+            credit: credit.clone(),
+            outbound: outbound.clone(),
+        };
+        let task = tokio::spawn(forwarding.run(settlements));
+        let settle = options.acks().then_some(settle);
+        self.subscriptions.insert(subscription, Forwarder { task, credit, settle });
+        Ok(())
+    }
+
+    /// Hands an ack or nack to the forwarding task of an ack-mode subscription.
+    fn settle(&self, subscription: u64, settlement: Settlement) -> Result<Reply, ApiError> {
+        let forwarder = self.subscriptions.get(&subscription)
+            .ok_or_else(|| ApiError::not_found(format!("Unknown subscription: {}", subscription)))?;
+        let settle = forwarder.settle.as_ref()
+            .ok_or_else(|| ApiError::invalid(format!("Subscription {} does not use acknowledgements", subscription)))?;
+        let _ = settle.send(settlement);
+        Ok(Reply::Ok)
+    }
+
+    async fn dispatch(
+        &mut self,
+        state: &DaemonState,
+        token: Option<&str>,
+        command: Command,
+    ) -> Result<Reply, ApiError> {
+        match command {
+            Command::Authenticate { token } => {
+                state.authorize(Some(&token), None).await?;
+                self.token = Some(token);
+                Ok(Reply::Ok)
+            }
+            Command::Login { user_id, password } => {
+                let token = state.login(&user_id, &password).await
+                    .ok_or_else(|| ApiError::new(ErrorCode::InvalidCredentials, "Invalid credentials"))?;
+                self.token = Some(token.clone());
+                Ok(Reply::Token { token })
+            }
+            Command::Emit { mut signal, ttl } => {
+                let emitter = state.authorize_signal(token, Permission::Write, &signal.name).await?;
+                signal.sender = Some(Sender::new(&emitter, self.peer));
+                state.publish(signal, ttl, Some(&emitter)).await?;
+                Ok(Reply::Ok)
+            }
+            Command::Schedule { mut signal, ttl, at } => {
+                let emitter = state.authorize_signal(token, Permission::Write, &signal.name).await?;
+                signal.sender = Some(Sender::new(&emitter, self.peer));
+                let scheduled = state.schedule_signal(signal, ttl, at, &emitter).await?;
+                Ok(Reply::Scheduled { scheduled: Box::new(scheduled) })
+            }
+            Command::ScheduledSignals => {
+                let caller = state.authorize(token, Some(Permission::Write)).await?;
+                Ok(Reply::ScheduledSignals { scheduled: state.list_scheduled(&caller).await })
+            }
+            Command::CancelScheduled { id } => {
+                let caller = state.authorize(token, Some(Permission::Write)).await?;
+                state.cancel_scheduled(id, &caller).await?;
+                Ok(Reply::Ok)
+            }
+            Command::AddCronJob { spec } => {
+                let caller = state.authorize(token, Some(Permission::Admin)).await?;
+                let job = state.add_cron_job(spec, &caller.user_id).await?;
+                Ok(Reply::CronJob { job: Box::new(job) })
+            }
+            Command::CronJobs => {
+                state.authorize(token, Some(Permission::Admin)).await?;
+                Ok(Reply::CronJobs { jobs: state.list_cron_jobs().await })
+            }
+            Command::RemoveCronJob { id } => {
+                state.authorize(token, Some(Permission::Admin)).await?;
+                state.remove_cron_job(id).await?;
+                Ok(Reply::Ok)
+            }
+            Command::PauseCronJob { id } => {
+                state.authorize(token, Some(Permission::Admin)).await?;
+                state.set_cron_job_paused(id, true).await?;
+                Ok(Reply::Ok)
+            }
+            Command::ResumeCronJob { id } => {
+                state.authorize(token, Some(Permission::Admin)).await?;
+                state.set_cron_job_paused(id, false).await?;
+                Ok(Reply::Ok)
+            }
+            Command::Listen { .. } => Err(ApiError::invalid("LISTEN is handled by the session")),
+            Command::Unsubscribe { subscription } => {
+                match self.subscriptions.remove(&subscription) {
+                    Some(forwarder) => {
+                        forwarder.task.abort();
+                        state.unsubscribe(subscription).await;
+                        Ok(Reply::Ok)
+                    }
+                    None => Err(ApiError::not_found(format!("Unknown subscription: {}", subscription))),
+                }
+            }
+            Command::Credit { subscription, count } => {
+                let forwarder = self.subscriptions.get(&subscription)
+                    .ok_or_else(|| ApiError::not_found(format!("Unknown subscription: {}", subscription)))?;
+                if let Some(credit) = &forwarder.credit {
+                    credit.add_permits(count as usize);
+                }
+                Ok(Reply::Ok)
+            }
+            Command::Ack { subscription, id } => self.settle(subscription, Settlement::Ack(id)),
+            Command::Nack { subscription, id, reason } => self.settle(subscription, Settlement::Nack(id, reason)),
+            Command::DeadLetters => {
+                let caller = state.authorize(token, Some(Permission::History)).await?;
+                let access = state.access(&caller).await;
+                let mut dead_letters = state.list_dead_letters().await;
+                dead_letters.retain(|dead_letter| {
+                    let name = &dead_letter.signal.signal.name;
+                    caller.allows_signal(&Permission::History, name) && access.can_access(name)
+                });
+                Ok(Reply::DeadLetters { dead_letters })
+            }
+            Command::DeadLetter { id } => {
+                state.authorize(token, Some(Permission::History)).await?;
+                // Dead letters out of the caller's scope look like missing ones.
+                let unknown = || ApiError::not_found(format!("Unknown dead letter: {}", id));
+                let dead_letter = state.get_dead_letter(id).await.ok_or_else(unknown)?;
+                state.authorize_signal(token, Permission::History, &dead_letter.signal.signal.name).await
+                    .map_err(|_| unknown())?;
+                Ok(Reply::DeadLetter { dead_letter: Box::new(dead_letter) })
+            }
+            Command::ReplayDeadLetters { id } => {
+                let caller = state.authorize(token, Some(Permission::Write)).await?;
+                Ok(Reply::Count { count: state.replay_dead_letters(id, &caller).await? })
+            }
+            Command::PurgeDeadLetters { id } => {
+                state.authorize(token, Some(Permission::Admin)).await?;
+                Ok(Reply::Count { count: state.purge_dead_letters(id).await? })
+            }
+            Command::ListDurables => {
+                let caller = state.authorize(token, Some(Permission::Read)).await?;
+                Ok(Reply::Durables { durables: state.list_durables(&caller).await })
+            }
+            Command::DeleteDurable { name } => {
+                let caller = state.authorize(token, Some(Permission::Read)).await?;
+                state.delete_durable(&name, &caller).await?;
+                Ok(Reply::Ok)
+            }
+            Command::History { pattern, limit } => {
+                let pattern = parse_pattern(&pattern)?;
+                let caller = state.authorize_pattern(token, Permission::History, &pattern).await?;
+                let signals = state.get_recent_signals(&pattern, limit, &state.access(&caller).await).await;
+                Ok(Reply::History { signals })
+            }
+            Command::SetRateLimit { pattern, max_signals, per_seconds } => {
+                state.authorize(token, Some(Permission::RateLimit)).await?;
+                state.set_rate_limit(parse_pattern(&pattern)?, max_signals, per_seconds).await;
+                Ok(Reply::Ok)
+            }
+            Command::RateLimits => {
+                state.authorize(token, Some(Permission::Read)).await?;
+                Ok(Reply::RateLimits { limits: state.list_rate_limits().await })
+            }
+            Command::CreateToken { user_id, permissions, scopes, expires_in } => {
+                state.authorize(token, Some(Permission::Admin)).await?;
+                let requested = if permissions.is_empty() {
+                    None
+                } else {
+                    Some(permissions_from(&permissions)?)
+                };
+                let scopes = parse_scopes(&scopes).map_err(|e| ApiError::invalid(e.to_string()))?;
+                let token = state.create_token(&user_id, requested, scopes, expires_in).await?;
+                Ok(Reply::Token { token })
+            }
+            Command::RevokeToken { token: token_to_revoke } => {
+                state.authorize(token, Some(Permission::Admin)).await?;
+                if state.revoke_token(&token_to_revoke).await {
+                    Ok(Reply::Ok)
+                } else {
+                    Err(ApiError::not_found("Token not found"))
+                }
+            }
+            Command::RevokeTokenId { id } => {
+                state.authorize(token, Some(Permission::Admin)).await?;
+                if state.revoke_token_id(&id).await {
+                    Ok(Reply::Ok)
+                } else {
+                    Err(ApiError::not_found("Token not found"))
+                }
+            }
+            Command::RevokeUserTokens { user_id } => {
+                state.authorize(token, Some(Permission::Admin)).await?;
+                Ok(Reply::Count { count: state.revoke_user_tokens(&user_id).await })
+            }
+            Command::ListTokens => {
+                state.authorize(token, Some(Permission::Admin)).await?;
+                Ok(Reply::Tokens { tokens: state.list_tokens().await })
+            }
+            Command::TokenInfo => {
+                let caller = state.authorize(token, None).await?;
+                Ok(Reply::TokenInfo { token: Box::new(TokenInfo::from(&caller)) })
+            }
+            Command::RefreshToken { expires_in } => {
+                let caller = state.authorize(token, None).await?;
+                let refreshed = state.refresh_token(&caller.token, expires_in).await?;
+                Ok(Reply::TokenInfo { token: Box::new(refreshed) })
+            }
+            Command::AddUser { user_id, password, permissions } => {
+                state.authorize(token, Some(Permission::Admin)).await?;
+                state.create_user(&user_id, &password, permissions_from(&permissions)?).await?;
+                Ok(Reply::Ok)
+            }
+            Command::SetPassword { user_id, password } => {
+                // Users may change their own password; changing others' needs Admin.
+                let caller = state.authorize(token, None).await?;
+                if caller.user_id != user_id {
+                    state.authorize(token, Some(Permission::Admin)).await?;
+                }
+                state.set_password(&user_id, &password).await?;
+                Ok(Reply::Ok)
+            }
+            Command::ListUsers => {
+                state.authorize(token, Some(Permission::Admin)).await?;
+                Ok(Reply::Users { users: state.list_users().await })
+            }
+            Command::DeleteUser { user_id } => {
+                state.authorize(token, Some(Permission::Admin)).await?;
+                if state.delete_user(&user_id).await {
+                    Ok(Reply::Ok)
+                } else {
+                    Err(ApiError::not_found("User not found"))
+                }
+            }
+            Command::SetUserPermissions { user_id, permissions } => {
+                state.authorize(token, Some(Permission::Admin)).await?;
+                state.set_user_permissions(&user_id, permissions_from(&permissions)?).await?;
+                Ok(Reply::Ok)
+            }
+            Command::GrantNamespace { user_id, owner } => {
+                state.authorize(token, Some(Permission::Admin)).await?;
+                state.set_namespace_grant(&user_id, &owner, true).await?;
+                Ok(Reply::Ok)
+            }
+            Command::RevokeNamespace { user_id, owner } => {
+                state.authorize(token, Some(Permission::Admin)).await?;
+                state.set_namespace_grant(&user_id, &owner, false).await?;
+                Ok(Reply::Ok)
+            }
+            Command::ExportState => {
+                state.authorize(token, Some(Permission::Admin)).await?;
+                Ok(Reply::State { snapshot: state.export_state().await })
+            }
+            Command::ImportState { snapshot } => {
+                state.authorize(token, Some(Permission::Admin)).await?;
+                state.import_state(snapshot).await?;
+                Ok(Reply::Ok)
+            }
+        }
+    }
+}
+
+/// Authorizes a `listen` request and starts the subscription it asks for.
+async fn start_listening(
+    state: &DaemonState,
+    token: Option<&str>,
+    pattern: &str,
+    options: &ListenOptions,
+) -> Result<(u64, Source, String), ApiError> {
+    let pattern = parse_pattern(pattern)?;
+    let caller = state.authorize_pattern(token, Permission::Read, &pattern).await?;
+    if options.replays() {
+        if options.durable.is_some() {
+            return Err(ApiError::invalid("A durable subscription resumes from its cursor and takes no replay options"));
+        }
+        // Replay reads history, so it needs the same permission as HISTORY.
+        state.authorize_pattern(token, Permission::History, &pattern).await?;
+    }
+
+    let access = state.access(&caller).await;
+
+    match &options.durable {
+        Some(_) if options.group.is_some() => Err(ApiError::invalid("A durable subscription cannot join a consumer group")),
+        Some(name) => {
+            let (subscription, attachment) = state.attach_durable(name, &pattern, &caller).await?;
+            let sent = attachment.cursor;
+            Ok((subscription, Source::Durable { attachment, sent, access }, caller.user_id))
+        }
+        None => {
+            let mut handle = state.subscribe(pattern, options, access).await?;
+            let replay = std::mem::take(&mut handle.replay).into_iter();
+            Ok((handle.id, Source::Live { handle, replay }, caller.user_id))
+        }
+    }
+}
+
+/// Where a subscription's signals come from.
+enum Source {
+    /// Replayed history, then the daemon's live queue.
+    Live {
+        handle: SubscriberHandle,
+        replay: std::vec::IntoIter<PersistentSignal>,
+    },
+    /// The buffer of a durable subscription, read past the last signal sent.
+    /// Unacknowledged signals are sent again on the next attach. The buffer
+    /// holds every match, so private signals the consumer may not see are
+    /// skipped here.
+    Durable { attachment: Attachment, sent: u64, access: Access },
+}
+
+impl Source {
+    /// The next signal to send, or `None` once the subscription has ended.
+    /// Cancel safe, so it can race acks and redelivery timers.
+    async fn next(&mut self, state: &DaemonState, subscription: u64) -> Option<PersistentSignal> {
+        match self {
+            Source::Live { handle, replay } => {
+                if let Some(signal) = replay.next() {
+                    return Some(signal);
+                }
+                loop {
+                    match handle.rx.recv().await {
+                        Ok(signal) if handle.seen.contains(&signal.id) => continue,
+                        Ok(signal) => return Some(signal),
+                        Err(_) => return None,
+                    }
+                }
+            }
+            Source::Durable { attachment, sent, access } => loop {
+                let notified = attachment.notify.notified();
+                match state.next_durable(subscription, *sent).await {
+                    Ok(Some(signal)) if !access.can_access(&signal.signal.name) => {
+                        *sent = signal.id;
+                        state.ack_durable(subscription, signal.id).await;
+                    }
+                    Ok(Some(signal)) => {
+                        *sent = signal.id;
+                        return Some(signal);
+                    }
+                    Ok(None) => notified.await,
+                    // The subscription was deleted while attached.
+                    Err(Detached) => return None,
+                }
+            },
+        }
+    }
+
+    /// Signals discarded by the overflow policy since the last call.
+    fn take_dropped(&self) -> u64 {
+        match self {
+            Source::Live { handle, .. } => handle.dropped.swap(0, Ordering::Relaxed),
+            Source::Durable { .. } => 0,
+        }
+    }
+}
+
+enum Settlement {
+    Ack(u64),
+    Nack(u64, Option<String>),
+}
+
+/// Signals sent to an ack-mode subscription and not yet acknowledged.
+struct Unacked {
+    timeout: Duration,
+    max_retries: u32,
+    signals: HashMap<u64, InFlight>,
+}
+
+struct InFlight {
+    signal: PersistentSignal,
+    /// When the current delivery times out; `None` once it failed and the
+    /// signal waits for credit to be delivered again.
+    deadline: Option<Instant>,
+    /// Failed deliveries so far, counting nacks and ack timeouts.
+    failures: u32,
+    /// Why the last delivery failed.
+    reason: Option<String>,
+}
+
+impl Unacked {
+    fn new(timeout: Duration, max_retries: u32) -> Self {
+        Unacked { timeout, max_retries, signals: HashMap::new() }
+    }
+
+    fn track(&mut self, signal: PersistentSignal) {
+        let deadline = Some(Instant::now() + self.timeout);
+        self.signals.insert(signal.id, InFlight { signal, deadline, failures: 0, reason: None });
+    }
+
+    fn ack(&mut self, id: u64) -> bool {
+        self.signals.remove(&id).is_some()
+    }
+
+    /// Counts a failure of the current delivery. The signal is redelivered
+    /// or dead-lettered by the next `expire`.
+    fn nack(&mut self, id: u64, reason: Option<String>) {
+        if let Some(in_flight) = self.signals.get_mut(&id)
+            && in_flight.deadline.is_some()
+        {
+            in_flight.fail(reason.unwrap_or_else(|| "nacked".to_string()));
+        }
+    }
+
+    /// Counts a failure for every delivery past its deadline and returns
+    /// the signals that failed more than `max_retries` times, for
+    /// dead-lettering. The others wait for `take_redelivery`.
+    fn expire(&mut self) -> Vec<InFlight> {
+        let now = Instant::now();
+        let reason = format!("not acknowledged within {:?}", self.timeout);
+        for in_flight in self.signals.values_mut() {
+            if in_flight.deadline.is_some_and(|deadline| deadline <= now) {
+                in_flight.fail(reason.clone());
+            }
+        }
+        let max_retries = self.max_retries;
+        let mut exhausted: Vec<u64> = self.signals.values()
+            .filter(|in_flight| in_flight.failures > max_retries)
+            .map(|in_flight| in_flight.signal.id)
+            .collect();
+        exhausted.sort_unstable();
+        exhausted.into_iter().filter_map(|id| self.signals.remove(&id)).collect()
+    }
+
+    /// The oldest signal waiting to be delivered again, tracked anew.
+    fn take_redelivery(&mut self) -> Option<PersistentSignal> {
+        let in_flight = self.signals.values_mut()
+            .filter(|in_flight| in_flight.deadline.is_none())
+            .min_by_key(|in_flight| in_flight.signal.id)?;
+        in_flight.deadline = Some(Instant::now() + self.timeout);
+        Some(in_flight.signal.clone())
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.signals.values().filter_map(|in_flight| in_flight.deadline).min()
+    }
+}
+
+impl InFlight {
+    fn fail(&mut self, reason: String) {
+        self.failures += 1;
+        self.reason = Some(reason);
+        self.deadline = None;
+    }
+}
+
+/// Who a subscription belongs to, recorded with its dead letters.
+struct Consumer {
+    pattern: String,
+    user_id: String,
+    durable: Option<String>,
+}
+
+/// Everything the forwarding task of one subscription owns.
+struct Forwarding {
+    state: Arc<DaemonState>,
+    subscription: u64,
+    consumer: Consumer,
+    source: Source,
+    /// `None` unless the subscription uses ack mode.
+    unacked: Option<Unacked>,
+    credit: Option<Arc<Semaphore>>,
+    outbound: mpsc::Sender<ServerMessage>,
+}
+
+impl Forwarding {
+    /// Sends the subscription's signals to the writer, redelivering overdue
+    /// ones in ack mode before anything new. Acks, nacks and ack timeouts are
+    /// handled while it waits for credit.
+    async fn run(mut self, mut settlements: mpsc::UnboundedReceiver<Settlement>) {
+        let subscription = self.subscription;
+        let credit = self.credit.clone();
+        // Whether the next event may be sent. Without credit the queue fills
+        // up and the overflow policy takes over.
+        let mut permitted = credit.is_none();
+
+        loop {
+            let next = loop {
+                let exhausted = self.unacked.as_mut().map(Unacked::expire).unwrap_or_default();
+                for in_flight in exhausted {
+                    self.dead_letter(in_flight).await;
+                }
+                if permitted
+                    && let Some(signal) = self.unacked.as_mut().and_then(Unacked::take_redelivery)
+                {
+                    break Some((signal, true));
+                }
+                let deadline = self.unacked.as_ref().and_then(Unacked::next_deadline);
+
+                tokio::select! {
+                    signal = self.source.next(&self.state, subscription), if permitted => {
+                        break signal.map(|signal| (signal, false));
+                    }
+                    Some(Ok(permit)) = acquire(credit.as_deref()), if !permitted => {
+                        permit.forget();
+                        permitted = true;
+                    }
+                    Some(settlement) = settlements.recv() => self.settle(settlement).await,
+                    _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {}
+                }
+            };
+            let Some((signal, redelivered)) = next else {
+                break;
+            };
+
+            let PersistentSignal { id, signal: body, .. } = signal.clone();
+            if !redelivered
+                && let Some(unacked) = &mut self.unacked
+            {
+                unacked.track(signal);
+            }
+            let dropped = self.source.take_dropped();
+            let event = ServerMessage::Event { subscription, id, signal: body, dropped, redelivered };
+            if self.outbound.send(event).await.is_err() {
+                self.state.unsubscribe(subscription).await;
+                return;
+            }
+            permitted = credit.is_none();
+        }
+
+        // The daemon closed the channel, e.g. under the disconnect policy.
+        let dropped = self.source.take_dropped();
+        let _ = self.outbound.send(ServerMessage::Closed { subscription, dropped }).await;
+        self.state.unsubscribe(subscription).await;
+    }
+
+    async fn settle(&mut self, settlement: Settlement) {
+        let Some(unacked) = &mut self.unacked else {
+            return;
+        };
+        match settlement {
+            Settlement::Ack(id) => {
+                if unacked.ack(id) {
+                    self.settled(id).await;
+                }
+            }
+            Settlement::Nack(id, reason) => unacked.nack(id, reason),
+        }
+    }
+
+    /// Moves a signal out of the way of later ones. A durable subscription
+    /// treats it as acknowledged so its cursor can pass it.
+    async fn dead_letter(&mut self, in_flight: InFlight) {
+        let id = in_flight.signal.id;
+        let dead_letter = DeadLetter {
+            id: 0,
+            signal: in_flight.signal,
+            pattern: self.consumer.pattern.clone(),
+            user_id: self.consumer.user_id.clone(),
+            durable: self.consumer.durable.clone(),
+            failures: in_flight.failures,
+            reason: in_flight.reason.unwrap_or_default(),
+            dead_lettered_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        };
+        self.state.dead_letter(dead_letter).await;
+        self.settled(id).await;
+    }
+
+    async fn settled(&self, id: u64) {
+        if matches!(self.source, Source::Durable { .. }) {
+            self.state.ack_durable(self.subscription, id).await;
+        }
+    }
+}
+
+/// Takes one unit of a prefetch window.
+async fn acquire(credit: Option<&Semaphore>) -> Option<Result<SemaphorePermit<'_>, AcquireError>> {
+    match credit {
+        Some(credit) => Some(credit.acquire().await),
+        None => None,
+    }
+}
+
+fn permissions_from(list: &[String]) -> Result<HashSet<Permission>, ApiError> {
+    parse_permissions(&list.join(",")).map_err(|e| ApiError::invalid(e.to_string()))
+}
+
+fn parse_pattern(pattern: &str) -> Result<Pattern, ApiError> {
+    Pattern::parse(pattern).map_err(|e| ApiError::invalid(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::DaemonConfig;
+    use crate::models::Signal;
+    use crate::wal::FsyncPolicy;
+
+    const WAIT: Duration = Duration::from_secs(2);
+
+    async fn memory_state() -> (Arc<DaemonState>, String) {
+        let config = DaemonConfig {
+            data_dir: None,
+            fsync: FsyncPolicy::Never,
+            admin_password_file: None,
+            insecure_default_admin: true,
+            peer_auth: None,
+        };
+        let state = DaemonState::new(&config).await.unwrap();
+        let token = state.generate_token("admin".to_string(), None).await;
+        (state, token)
+    }
+
+    /// Starts a subscription the way a `listen` request does and returns
+    /// the session, its outbound events and the subscription id.
+    async fn listen(
+        state: &Arc<DaemonState>,
+        token: &str,
+        options: ListenOptions,
+    ) -> (Session, mpsc::Receiver<ServerMessage>, u64) {
+        let mut session = Session { token: Some(token.to_string()), peer: None, subscriptions: HashMap::new() };
+        let (outbound, mut events) = mpsc::channel(OUTBOUND_CAPACITY);
+        session.listen(state, 1, Some(token), "test.*".to_string(), options, &outbound).await.unwrap();
+        match events.recv().await {
+            Some(ServerMessage::Response { outcome: Outcome::Ok(Reply::Listening { subscription }), .. }) => {
+                (session, events, subscription)
+            }
+            other => panic!("expected a listening response, got {:?}", other),
+        }
+    }
+
+    /// The id of the next event and whether it was a redelivery.
+    async fn next_event(events: &mut mpsc::Receiver<ServerMessage>) -> (u64, bool) {
+        match tokio::time::timeout(WAIT, events.recv()).await {
+            Ok(Some(ServerMessage::Event { id, redelivered, .. })) => (id, redelivered),
+            other => panic!("expected an event, got {:?}", other),
+        }
+    }
+
+    async fn emit(state: &DaemonState, name: &str) {
+        state.publish(Signal::new(name.to_string(), None).unwrap(), None, None).await.unwrap();
+    }
+
+    async fn wait_for_dead_letter(state: &DaemonState) -> DeadLetter {
+        let deadline = Instant::now() + WAIT;
+        loop {
+            if let Some(dead_letter) = state.list_dead_letters().await.pop() {
+                return dead_letter;
+            }
+            assert!(Instant::now() < deadline, "no dead letter");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn nacks_are_settled_without_credit() {
+        let (state, token) = memory_state().await;
+        let options = ListenOptions { ack: true, prefetch: Some(1), max_retries: Some(0), ..Default::default() };
+        let (session, mut events, subscription) = listen(&state, &token, options).await;
+
+        emit(&state, "test.one").await;
+        let (id, _) = next_event(&mut events).await;
+        session.settle(subscription, Settlement::Nack(id, Some("broken".to_string()))).unwrap();
+
+        let dead_letter = wait_for_dead_letter(&state).await;
+        assert_eq!(dead_letter.signal.id, id);
+        assert_eq!(dead_letter.reason, "broken");
+    }
+
+    #[tokio::test]
+    async fn ack_timeouts_fire_without_credit() {
+        let (state, token) = memory_state().await;
+        let options = ListenOptions {
+            ack: true,
+            prefetch: Some(1),
+            ack_timeout_ms: Some(20),
+            max_retries: Some(0),
+            ..Default::default()
+        };
+        let (_session, mut events, _) = listen(&state, &token, options).await;
+
+        emit(&state, "test.one").await;
+        let (id, _) = next_event(&mut events).await;
+
+        let dead_letter = wait_for_dead_letter(&state).await;
+        assert_eq!(dead_letter.signal.id, id);
+        assert_eq!(dead_letter.failures, 1);
+    }
+
+    #[tokio::test]
+    async fn ack_timeout_redelivers_marked_as_redelivered() {
+        let (state, token) = memory_state().await;
+        let options = ListenOptions { ack: true, ack_timeout_ms: Some(200), ..Default::default() };
+        let (session, mut events, subscription) = listen(&state, &token, options).await;
+
+        emit(&state, "test.one").await;
+        let (id, redelivered) = next_event(&mut events).await;
+        assert!(!redelivered);
+        assert_eq!(next_event(&mut events).await, (id, true));
+
+        session.settle(subscription, Settlement::Ack(id)).unwrap();
+        emit(&state, "test.two").await;
+        let (next, redelivered) = next_event(&mut events).await;
+        assert!(next > id && !redelivered);
+    }
+
+    #[tokio::test]
+    async fn nacked_signal_is_dead_lettered_after_max_retries() {
+        let (state, token) = memory_state().await;
+        let options = ListenOptions { ack: true, max_retries: Some(2), ..Default::default() };
+        let (session, mut events, subscription) = listen(&state, &token, options).await;
+
+        emit(&state, "test.one").await;
+        let (id, _) = next_event(&mut events).await;
+        for _ in 0..2 {
+            session.settle(subscription, Settlement::Nack(id, None)).unwrap();
+            assert_eq!(next_event(&mut events).await, (id, true));
+        }
+        session.settle(subscription, Settlement::Nack(id, Some("still broken".to_string()))).unwrap();
+
+        let dead_letter = wait_for_dead_letter(&state).await;
+        assert_eq!(dead_letter.signal.id, id);
+        assert_eq!(dead_letter.failures, 3);
+        assert_eq!(dead_letter.reason, "still broken");
+        assert_eq!(dead_letter.pattern, "test.*");
+    }
+
+    #[tokio::test]
+    async fn dead_lettering_lets_a_durable_cursor_pass() {
+        let (state, token) = memory_state().await;
+        let options = ListenOptions {
+            ack: true,
+            max_retries: Some(0),
+            durable: Some("jobs".to_string()),
+            ..Default::default()
+        };
+        let (session, mut events, subscription) = listen(&state, &token, options).await;
+
+        emit(&state, "test.one").await;
+        let (id, _) = next_event(&mut events).await;
+        session.settle(subscription, Settlement::Nack(id, None)).unwrap();
+        assert_eq!(wait_for_dead_letter(&state).await.durable.as_deref(), Some("jobs"));
+
+        let admin = state.token_info(&token).await.unwrap();
+        let deadline = Instant::now() + WAIT;
+        while state.list_durables(&admin).await[0].cursor < id {
+            assert!(Instant::now() < deadline, "cursor did not move");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[test]
+    fn a_nack_counts_once_per_delivery() {
+        let mut unacked = Unacked::new(Duration::from_secs(60), 1);
+        unacked.track(PersistentSignal { signal: Signal::new("test.one".to_string(), None).unwrap(), id: 1, ttl: None });
+        unacked.nack(1, None);
+        unacked.nack(1, None);
+        assert!(unacked.expire().is_empty());
+        assert_eq!(unacked.take_redelivery().map(|signal| signal.id), Some(1));
+        assert!(unacked.take_redelivery().is_none());
+
+        unacked.nack(1, None);
+        let exhausted = unacked.expire();
+        assert_eq!(exhausted.len(), 1);
+        assert_eq!(exhausted[0].failures, 2);
+        assert!(!unacked.ack(1));
+    }
+}
@@ -0,0 +1,70 @@
+use crate::models::{AuthToken, DurableSubscription, RateLimitInfo, User};
+use crate::wal::sync_dir;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+const STATE_FILE: &str = "state.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StateSnapshot {
+    pub users: Vec<User>,
+    pub tokens: Vec<AuthToken>,
+    pub default_tokens: HashMap<String, String>,
+    pub rate_limits: Vec<RateLimitInfo>,
+    #[serde(default)]
+    pub durables: Vec<DurableSubscription>,
+}
+
+pub struct StateStore {
+    dir: PathBuf,
+    path: PathBuf,
+}
+
+impl StateStore {
+    pub async fn open(dir: &Path) -> Result<(Self, Option<StateSnapshot>)> {
+        fs::create_dir_all(dir).await?;
+        let path = dir.join(STATE_FILE);
+
+        let snapshot = match fs::read(&path).await {
+            Ok(bytes) => Some(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        let store = StateStore {
+            dir: dir.to_path_buf(),
+            path,
+        };
+        Ok((store, snapshot))
+    }
+
+    /// Replaces the state file atomically. The file holds tokens, so it is
+    /// only readable by the daemon's user.
+    pub async fn save(&self, snapshot: &StateSnapshot) -> Result<()> {
+        write_atomic(&self.dir, &self.path, &serde_json::to_vec_pretty(snapshot)?).await
+    }
+}
+
+/// Writes and fsyncs `bytes` to a temporary file which is then renamed over
+/// `path`, so readers see either the old or the new contents. Files are only
+/// readable by the daemon's user.
+pub async fn write_atomic(dir: &Path, path: &Path, bytes: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)
+        .await?;
+    file.write_all(bytes).await?;
+    file.flush().await?;
+    file.sync_all().await?;
+    fs::rename(&tmp, path).await?;
+    sync_dir(dir).await?;
+    Ok(())
+}
@@ -0,0 +1,425 @@
+use crate::models::PersistentSignal;
+use anyhow::Result;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+const SEGMENT_EXTENSION: &str = "log";
+const DEFAULT_SEGMENT_MAX_BYTES: u64 = 4 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum FsyncPolicy {
+    /// fsync after every appended signal
+    Always,
+    /// fsync from a background task once per second
+    Periodic,
+    /// leave flushing to the operating system
+    Never,
+}
+
+struct Segment {
+    base_id: u64,
+    path: PathBuf,
+}
+
+pub struct Wal {
+    dir: PathBuf,
+    policy: FsyncPolicy,
+    segment_max_bytes: u64,
+    sealed: Vec<Segment>,
+    active: Segment,
+    active_file: File,
+    active_size: u64,
+    dirty: bool,
+}
+
+pub struct Replay {
+    pub signals: Vec<PersistentSignal>,
+    pub next_id: u64,
+}
+
+impl Wal {
+    pub async fn open(dir: &Path, policy: FsyncPolicy) -> Result<(Self, Replay)> {
+        fs::create_dir_all(dir).await?;
+
+        let mut segments = list_segments(dir).await?;
+        let mut signals = Vec::new();
+        let mut next_id = 1;
+
+        let last = segments.len().saturating_sub(1);
+        for (index, segment) in segments.iter().enumerate() {
+            next_id = next_id.max(segment.base_id);
+            for ps in read_segment(&segment.path, index == last).await? {
+                next_id = next_id.max(ps.id + 1);
+                signals.push(ps);
+            }
+        }
+
+        let active = match segments.pop() {
+            Some(segment) => segment,
+            None => Segment {
+                base_id: next_id,
+                path: segment_path(dir, next_id),
+            },
+        };
+
+        let active_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&active.path)
+            .await?;
+        let active_size = active_file.metadata().await?.len();
+
+        println!("[WAL] Replayed {} signals from {} segments in {}",
+                 signals.len(), segments.len() + 1, dir.display());
+
+        let wal = Wal {
+            dir: dir.to_path_buf(),
+            policy,
+            segment_max_bytes: DEFAULT_SEGMENT_MAX_BYTES,
+            sealed: segments,
+            active,
+            active_file,
+            active_size,
+            dirty: false,
+        };
+
+        Ok((wal, Replay { signals, next_id }))
+    }
+
+    pub fn policy(&self) -> FsyncPolicy {
+        self.policy
+    }
+
+    pub async fn append(&mut self, ps: &PersistentSignal) -> Result<()> {
+        if self.active_size >= self.segment_max_bytes {
+            self.roll(ps.id).await?;
+        }
+
+        let mut record = serde_json::to_vec(ps)?;
+        record.push(b'\n');
+
+        self.active_file.write_all(&record).await?;
+        self.active_file.flush().await?;
+        self.active_size += record.len() as u64;
+        self.dirty = true;
+
+        if self.policy == FsyncPolicy::Always {
+            self.sync().await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn sync(&mut self) -> Result<()> {
+        if self.dirty {
+            self.active_file.sync_data().await?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    async fn roll(&mut self, next_id: u64) -> Result<()> {
+        self.active_file.sync_data().await?;
+        self.dirty = false;
+
+        let path = segment_path(&self.dir, next_id);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        sync_dir(&self.dir).await?;
+
+        let previous = std::mem::replace(&mut self.active, Segment { base_id: next_id, path });
+        self.sealed.push(previous);
+        self.active_file = file;
+        self.active_size = 0;
+        Ok(())
+    }
+
+    /// Drops sealed segments that no longer hold any live signal and rewrites
+    /// the ones where most records are dead. The active segment is left alone.
+    pub async fn compact(&mut self, live: &HashSet<u64>) -> Result<()> {
+        let mut kept = Vec::with_capacity(self.sealed.len());
+        let mut removed = 0;
+        let mut rewritten = 0;
+
+        for segment in std::mem::take(&mut self.sealed) {
+            let records = read_segment(&segment.path, false).await?;
+            let total = records.len();
+            let alive: Vec<PersistentSignal> = records
+                .into_iter()
+                .filter(|ps| live.contains(&ps.id))
+                .collect();
+
+            if alive.is_empty() {
+                fs::remove_file(&segment.path).await?;
+                removed += 1;
+                continue;
+            }
+
+            if alive.len() * 2 < total {
+                rewrite_segment(&segment.path, &alive).await?;
+                rewritten += 1;
+            }
+            kept.push(segment);
+        }
+
+        self.sealed = kept;
+        if removed > 0 || rewritten > 0 {
+            sync_dir(&self.dir).await?;
+            println!("[WAL] Compaction removed {} and rewrote {} segments", removed, rewritten);
+        }
+        Ok(())
+    }
+}
+
+/// Drops replayed signals whose TTL has passed and keeps at most `max` of the newest.
+pub fn retain_live(signals: &mut Vec<PersistentSignal>, max: usize) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    signals.retain(|ps| ps.is_live(now));
+
+    if signals.len() > max {
+        signals.drain(..signals.len() - max);
+    }
+}
+
+fn segment_path(dir: &Path, base_id: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", base_id, SEGMENT_EXTENSION))
+}
+
+async fn list_segments(dir: &Path) -> Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    let mut entries = fs::read_dir(dir).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+            continue;
+        }
+        let base_id = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse().ok());
+        if let Some(base_id) = base_id {
+            segments.push(Segment { base_id, path });
+        }
+    }
+
+    segments.sort_by_key(|s| s.base_id);
+    Ok(segments)
+}
+
+/// Reads every record of a segment. Corrupt records are logged and skipped
+/// so one bad line never costs the records after it. With `repair`, used
+/// for the active segment, a torn record at the tail, left behind by a
+/// crash mid-write, is truncated away so appends start clean.
+async fn read_segment(path: &Path, repair: bool) -> Result<Vec<PersistentSignal>> {
+    let file = File::open(path).await?;
+    let mut reader = BufReader::new(file);
+    let mut records = Vec::new();
+    let mut complete_len = 0u64;
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line).await?;
+        if read == 0 || !line.ends_with(b"\n") {
+            break;
+        }
+        complete_len += read as u64;
+        match serde_json::from_slice::<PersistentSignal>(&line) {
+            Ok(ps) => records.push(ps),
+            Err(e) => eprintln!("[WAL] Skipping corrupt record in {}: {}", path.display(), e),
+        }
+    }
+
+    let file_len = fs::metadata(path).await?.len();
+    if complete_len < file_len {
+        if repair {
+            eprintln!("[WAL] Truncating torn tail of {} from {} to {} bytes", path.display(), file_len, complete_len);
+            let file = OpenOptions::new().write(true).open(path).await?;
+            file.set_len(complete_len).await?;
+            file.sync_all().await?;
+        } else {
+            eprintln!("[WAL] Ignoring incomplete record at the end of {}", path.display());
+        }
+    }
+
+    Ok(records)
+}
+
+async fn rewrite_segment(path: &Path, records: &[PersistentSignal]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp).await?;
+    for ps in records {
+        let mut record = serde_json::to_vec(ps)?;
+        record.push(b'\n');
+        file.write_all(&record).await?;
+    }
+    file.flush().await?;
+    file.sync_all().await?;
+    fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+pub(crate) async fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir).await?.sync_all().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Signal;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn temp_dir() -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "signalbus-wal-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn signal(id: u64) -> PersistentSignal {
+        PersistentSignal {
+            signal: Signal::new(format!("test:{}", id), None).unwrap(),
+            id,
+            ttl: None,
+        }
+    }
+
+    fn ids(signals: &[PersistentSignal]) -> Vec<u64> {
+        signals.iter().map(|ps| ps.id).collect()
+    }
+
+    #[tokio::test]
+    async fn replays_appended_signals() {
+        let dir = temp_dir();
+        let (mut wal, replay) = Wal::open(&dir, FsyncPolicy::Never).await.unwrap();
+        assert!(replay.signals.is_empty());
+        assert_eq!(replay.next_id, 1);
+        for id in 1..=3 {
+            wal.append(&signal(id)).await.unwrap();
+        }
+        drop(wal);
+
+        let (_, replay) = Wal::open(&dir, FsyncPolicy::Never).await.unwrap();
+        assert_eq!(ids(&replay.signals), vec![1, 2, 3]);
+        assert_eq!(replay.next_id, 4);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn truncates_torn_tail_of_active_segment() {
+        let dir = temp_dir();
+        let (mut wal, _) = Wal::open(&dir, FsyncPolicy::Never).await.unwrap();
+        wal.append(&signal(1)).await.unwrap();
+        let path = wal.active.path.clone();
+        drop(wal);
+
+        let intact = std::fs::metadata(&path).unwrap().len();
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.extend_from_slice(b"{\"signal\":{\"na");
+        std::fs::write(&path, bytes).unwrap();
+
+        let (mut wal, replay) = Wal::open(&dir, FsyncPolicy::Never).await.unwrap();
+        assert_eq!(ids(&replay.signals), vec![1]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), intact);
+
+        wal.append(&signal(2)).await.unwrap();
+        drop(wal);
+        let (_, replay) = Wal::open(&dir, FsyncPolicy::Never).await.unwrap();
+        assert_eq!(ids(&replay.signals), vec![1, 2]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn skips_corrupt_record_in_sealed_segment() {
+        let dir = temp_dir();
+        let (mut wal, _) = Wal::open(&dir, FsyncPolicy::Never).await.unwrap();
+        wal.segment_max_bytes = 1;
+        for id in 1..=4 {
+            wal.append(&signal(id)).await.unwrap();
+        }
+        let sealed = wal.sealed[0].path.clone();
+        drop(wal);
+
+        // Corrupt the only record of the first sealed segment, then add a
+        // valid one after it.
+        let mut bytes = b"not json\n".to_vec();
+        bytes.extend(serde_json::to_vec(&signal(5)).unwrap());
+        bytes.push(b'\n');
+        std::fs::write(&sealed, &bytes).unwrap();
+
+        let (_, replay) = Wal::open(&dir, FsyncPolicy::Never).await.unwrap();
+        assert_eq!(ids(&replay.signals), vec![5, 2, 3, 4]);
+        assert_eq!(std::fs::read(&sealed).unwrap(), bytes);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn compaction_drops_dead_segments_and_rewrites_sparse_ones() {
+        let dir = temp_dir();
+        let (mut wal, _) = Wal::open(&dir, FsyncPolicy::Never).await.unwrap();
+        wal.segment_max_bytes = 1;
+        for id in 1..=3 {
+            wal.append(&signal(id)).await.unwrap();
+        }
+        // Keep the third segment open for two more records.
+        wal.segment_max_bytes = u64::MAX;
+        wal.append(&signal(4)).await.unwrap();
+        wal.append(&signal(5)).await.unwrap();
+        wal.segment_max_bytes = 1;
+        wal.append(&signal(6)).await.unwrap();
+        assert_eq!(ids(&read_segment(&wal.sealed[2].path, false).await.unwrap()), vec![3, 4, 5]);
+
+        // Segment 1 is dead, segment 2 is fully live and segment 3 keeps
+        // one of three records, so it is rewritten. The active one is kept.
+        let live: HashSet<u64> = [2, 5].into_iter().collect();
+        let third = wal.sealed[2].path.clone();
+        wal.compact(&live).await.unwrap();
+        assert_eq!(wal.sealed.len(), 2);
+        assert_eq!(ids(&read_segment(&third, false).await.unwrap()), vec![5]);
+        drop(wal);
+
+        let (_, replay) = Wal::open(&dir, FsyncPolicy::Never).await.unwrap();
+        assert_eq!(ids(&replay.signals), vec![2, 5, 6]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn retain_live_drops_expired_and_keeps_newest() {
+        let mut signals: Vec<PersistentSignal> = (1..=4).map(signal).collect();
+        signals[0].ttl = Some(1);
+        signals[0].signal.timestamp = 0;
+        retain_live(&mut signals, 2);
+        assert_eq!(ids(&signals), vec![3, 4]);
+    }
+
+    #[tokio::test]
+    async fn replays_signal_with_huge_ttl() {
+        let dir = temp_dir();
+        let (mut wal, _) = Wal::open(&dir, FsyncPolicy::Never).await.unwrap();
+        let mut forever = signal(1);
+        forever.ttl = Some(u64::MAX);
+        wal.append(&forever).await.unwrap();
+        drop(wal);
+
+        let (_, mut replay) = Wal::open(&dir, FsyncPolicy::Never).await.unwrap();
+        retain_live(&mut replay.signals, 10);
+        assert_eq!(ids(&replay.signals), vec![1]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}